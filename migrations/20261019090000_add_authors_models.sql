@@ -0,0 +1,29 @@
+-- Add migration script here
+
+-- SeaArt authors, keyed by their upstream id
+CREATE TABLE authors (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    avatar TEXT,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_date TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- SeaArt models, keyed by their upstream model_id
+CREATE TABLE models (
+    id TEXT PRIMARY KEY,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Link images to their author and model, and keep the engagement stats
+ALTER TABLE ipfs_image
+    ADD COLUMN author_id TEXT REFERENCES authors (id),
+    ADD COLUMN model_id TEXT REFERENCES models (id),
+    ADD COLUMN channel TEXT,
+    ADD COLUMN num_of_like INT NOT NULL DEFAULT 0,
+    ADD COLUMN num_of_collection INT NOT NULL DEFAULT 0,
+    ADD COLUMN num_of_view INT NOT NULL DEFAULT 0;
+
+CREATE INDEX author_id_hash_index ON ipfs_image USING hash (author_id);
+
+CREATE INDEX model_id_hash_index ON ipfs_image USING hash (model_id);
@@ -0,0 +1,13 @@
+-- Add migration script here
+
+-- Last page reached by a crawl, so the next crawl with the same search can resume
+CREATE TABLE crawl_progress (
+    id SERIAL PRIMARY KEY,
+    keyword TEXT NOT NULL,
+    tags TEXT NOT NULL DEFAULT '',
+    category TEXT NOT NULL DEFAULT '',
+    last_page INT NOT NULL CHECK (last_page >= 0),
+    total_inserted INT NOT NULL DEFAULT 0,
+    updated_date TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (keyword, tags, category)
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- Normalized content rating derived from the SeaArt nsfw flags. Rows stored before it was
+-- rated, and rows inserted without a rating, are `unrated` and stay out of the default listing.
+ALTER TABLE ipfs_image
+    ADD COLUMN content_rating TEXT NOT NULL DEFAULT 'unrated'
+        CHECK (content_rating IN ('safe', 'sensitive', 'explicit', 'unrated')),
+    ADD COLUMN quarantined BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX content_rating_hash_index ON ipfs_image USING hash (content_rating);
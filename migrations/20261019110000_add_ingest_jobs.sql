@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- Background ingest jobs started from POST /ingest
+CREATE TABLE ingest_jobs (
+    id SERIAL PRIMARY KEY,
+    params JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'finished', 'failed', 'cancelled')),
+    pages_crawled INT NOT NULL DEFAULT 0,
+    last_page INT,
+    total_inserted INT NOT NULL DEFAULT 0,
+    total_duplicate INT NOT NULL DEFAULT 0,
+    total_failure INT NOT NULL DEFAULT 0,
+    total_skipped INT NOT NULL DEFAULT 0,
+    total_quarantined INT NOT NULL DEFAULT 0,
+    errors JSONB NOT NULL DEFAULT '[]',
+    stop_reason TEXT,
+    error_message TEXT,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX ingest_jobs_status_index ON ingest_jobs (status);
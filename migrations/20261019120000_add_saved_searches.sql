@@ -0,0 +1,27 @@
+-- Add migration script here
+
+-- SeaArt searches re-ingested on a cron-style schedule
+CREATE TABLE saved_searches (
+    id SERIAL PRIMARY KEY,
+    keyword TEXT NOT NULL DEFAULT '',
+    tags TEXT[] NOT NULL DEFAULT '{}',
+    category TEXT,
+    order_by TEXT NOT NULL DEFAULT 'hot',
+    schedule TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    last_run_at TIMESTAMPTZ,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_date TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- One row per scheduled run, the ingest job holds the counts of a started run
+CREATE TABLE saved_search_runs (
+    id SERIAL PRIMARY KEY,
+    saved_search_id INT NOT NULL REFERENCES saved_searches (id) ON DELETE CASCADE,
+    job_id INT REFERENCES ingest_jobs (id),
+    status TEXT NOT NULL CHECK (status IN ('started', 'skipped', 'failed')),
+    message TEXT,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX saved_search_runs_search_index ON saved_search_runs (saved_search_id);
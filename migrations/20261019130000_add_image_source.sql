@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- Which upstream gallery a row came from, everything ingested so far is SeaArt
+ALTER TABLE ipfs_image
+    ADD COLUMN source TEXT NOT NULL DEFAULT 'seaart';
+
+ALTER TABLE ipfs_image
+    ALTER COLUMN source SET DEFAULT 'manual';
+
+CREATE INDEX source_hash_id_index ON ipfs_image (source, hash_id);
+
+-- Crawl progress is tracked per source as well
+ALTER TABLE crawl_progress
+    ADD COLUMN source TEXT NOT NULL DEFAULT 'seaart';
+
+ALTER TABLE crawl_progress
+    DROP CONSTRAINT crawl_progress_keyword_tags_category_key,
+    ADD CONSTRAINT crawl_progress_source_keyword_tags_category_key
+        UNIQUE (source, keyword, tags, category);
@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- Normalized tags, names are stored trimmed and lowercased
+CREATE TABLE tags (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE image_tags (
+    image_id INT NOT NULL REFERENCES ipfs_image (id) ON DELETE CASCADE,
+    tag_id INT NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+    PRIMARY KEY (image_id, tag_id)
+);
+
+CREATE INDEX image_tags_tag_index ON image_tags (tag_id);
@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- Terms, attention weights, networks and negative prompt parsed out of `prompt`
+ALTER TABLE ipfs_image
+    ADD COLUMN parsed_prompt JSONB;
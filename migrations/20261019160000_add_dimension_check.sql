@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- Dimensions read from the downloaded file replace the ones reported by the source,
+-- the reported values are kept when they disagreed
+ALTER TABLE ipfs_image
+    ADD COLUMN metadata_width INT,
+    ADD COLUMN metadata_height INT,
+    ADD COLUMN dimensions_mismatch BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN dimensions_verified_at TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- 64 bit difference hash of the image file, near_duplicate_of points at the stored image it matched
+ALTER TABLE ipfs_image
+    ADD COLUMN phash BIGINT,
+    ADD COLUMN near_duplicate_of INT REFERENCES ipfs_image (id) ON DELETE SET NULL;
+
+ALTER TABLE ingest_jobs
+    ADD COLUMN total_near_duplicate INT NOT NULL DEFAULT 0;
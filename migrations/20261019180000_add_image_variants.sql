@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- Resized renditions of an image, written to VARIANT_DIR and served under VARIANT_BASE_URL
+CREATE TABLE IF NOT EXISTS image_variants (
+    id SERIAL PRIMARY KEY,
+    image_id INT NOT NULL REFERENCES ipfs_image (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    format TEXT NOT NULL,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    path TEXT NOT NULL,
+    url TEXT NOT NULL,
+    bytes INT NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (image_id, kind, format, width)
+);
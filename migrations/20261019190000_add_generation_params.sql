@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- Settings read from the `parameters` text chunk of downloaded PNGs
+ALTER TABLE ipfs_image
+    ADD COLUMN generation_params JSONB;
@@ -0,0 +1,13 @@
+-- Add migration script here
+
+-- Dominant colors of an image in CIE L*a*b*, position 0 covers the most pixels
+CREATE TABLE IF NOT EXISTS image_colors (
+    image_id INT NOT NULL REFERENCES ipfs_image (id) ON DELETE CASCADE,
+    position INT NOT NULL,
+    hex TEXT NOT NULL,
+    l DOUBLE PRECISION NOT NULL,
+    a DOUBLE PRECISION NOT NULL,
+    b DOUBLE PRECISION NOT NULL,
+    share DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (image_id, position)
+);
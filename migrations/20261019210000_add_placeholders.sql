@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- Shown by the front end while the image loads
+ALTER TABLE ipfs_image
+    ADD COLUMN blurhash TEXT,
+    ADD COLUMN average_color TEXT;
@@ -0,0 +1,30 @@
+-- Add migration script here
+
+-- Categories, images reference them by slug so the column keeps its readable value
+CREATE TABLE categories (
+    id SERIAL PRIMARY KEY,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    description TEXT,
+    parent_id INT REFERENCES categories (id) ON DELETE SET NULL CHECK (parent_id <> id),
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_date TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX categories_parent_index ON categories (parent_id);
+
+-- Every stored spelling starts out as its own category so no image loses its value, the
+-- server merges them into `slugify` slugs on startup since SQL has no exact match for it
+INSERT INTO categories (slug, name)
+SELECT DISTINCT category, category
+FROM ipfs_image
+WHERE category IS NOT NULL;
+
+-- Renaming a slug follows through to the images, deleting a category uncategorizes them
+ALTER TABLE ipfs_image
+    ADD CONSTRAINT ipfs_image_category_fkey FOREIGN KEY (category)
+    REFERENCES categories (slug) ON UPDATE CASCADE ON DELETE SET NULL;
+
+DROP INDEX category_hash_index;
+
+CREATE INDEX category_index ON ipfs_image (category);
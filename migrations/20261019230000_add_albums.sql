@@ -0,0 +1,23 @@
+-- Add migration script here
+
+-- Hand picked, ordered sets of images, independent of categories
+CREATE TABLE albums (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT,
+    cover_image_id INT REFERENCES ipfs_image (id) ON DELETE SET NULL,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_date TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE album_items (
+    album_id INT NOT NULL REFERENCES albums (id) ON DELETE CASCADE,
+    image_id INT NOT NULL REFERENCES ipfs_image (id) ON DELETE CASCADE,
+    position INT NOT NULL,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (album_id, image_id)
+);
+
+CREATE INDEX album_items_position_index ON album_items (album_id, position);
+
+CREATE INDEX album_items_image_index ON album_items (image_id);
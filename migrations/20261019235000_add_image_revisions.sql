@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- Old and new values of every change made to an image row through update_data
+CREATE TABLE ipfs_image_revisions (
+    id SERIAL PRIMARY KEY,
+    image_id INT NOT NULL REFERENCES ipfs_image (id) ON DELETE CASCADE,
+    old_image TEXT NOT NULL,
+    new_image TEXT NOT NULL,
+    old_ipfs_image_url TEXT NOT NULL,
+    new_ipfs_image_url TEXT NOT NULL,
+    old_category TEXT,
+    new_category TEXT,
+    actor TEXT NOT NULL,
+    -- The revision this one undid, for reverts
+    reverted_revision_id INT REFERENCES ipfs_image_revisions (id) ON DELETE SET NULL,
+    time_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX ipfs_image_revisions_image_index ON ipfs_image_revisions (image_id, id);
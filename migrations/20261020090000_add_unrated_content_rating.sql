@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- Images from sources without rating information, such as scraped pages, are `unrated`
+ALTER TABLE ipfs_image
+    DROP CONSTRAINT ipfs_image_content_rating_check,
+    ADD CONSTRAINT ipfs_image_content_rating_check
+        CHECK (content_rating IN ('safe', 'sensitive', 'explicit', 'unrated'));
+
+-- Scraped rows were stored as safe although nothing rated them
+UPDATE ipfs_image
+SET content_rating = 'unrated'
+WHERE source = 'html' AND content_rating = 'safe';
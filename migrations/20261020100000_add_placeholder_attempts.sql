@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- The placeholder backfill gives up on files that keep failing instead of downloading them on every start
+ALTER TABLE ipfs_image
+    ADD COLUMN placeholder_attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN placeholder_failed_at TIMESTAMPTZ;
@@ -1,27 +1,111 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
+    body::StreamBody,
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LOCATION, CONTENT_TYPE, ETAG},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
     Json,
 };
 
+mod albums;
+mod archive;
+mod categories;
+mod content_rating;
+mod crawl;
+mod fetch_guard;
+mod generation_params;
+mod html_source;
+mod image_analysis;
+mod image_download;
+mod image_source;
 mod ipfs_model;
+mod ipfs_storage;
+mod jobs;
+mod near_duplicate;
+mod palette;
+mod placeholder;
+mod preconditions;
+mod preview;
+mod prompt;
+mod render;
+mod revisions;
+mod saved_search;
 mod seaart_resp;
+mod similar;
+mod tags;
+mod upstream;
+mod variants;
 
-use crate::internal_error;
+use crate::{internal_error, AppState};
 
-use ipfs_model::{ArrStructData, Operation, OperationResult, ReturnJson};
-use serde::{Deserialize, Serialize};
+use ipfs_model::{
+    ArrStructData, ColorFilter, ListFilter, Operation, OperationResult, ReturnJson, TagFacet,
+};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
-use sqlx::{Pool, Postgres};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Pool, Postgres,
+};
+use tokio_util::io::ReaderStream;
 
 use ArrStructData::*;
 use OperationResult::*;
 
-use self::seaart_resp::{extract_obj, get_raw_value};
+pub use self::content_rating::IngestPolicy;
 
-#[derive(Deserialize, Debug)]
+use self::content_rating::ContentRating;
+pub use self::jobs::{fail_interrupted_jobs, JobRegistry};
+pub use self::near_duplicate::DuplicatePolicy;
+
+pub use self::categories::normalize_categories;
+pub use self::fetch_guard::FetchGuard;
+pub use self::html_source::HtmlSource;
+pub use self::image_analysis::backfill_placeholders;
+pub use self::image_download::ImageDownloader;
+pub use self::ipfs_storage::IpfsStorage;
+pub use self::preconditions::PreconditionPolicy;
+pub use self::prompt::backfill_parsed_prompts;
+pub use self::render::RenderConfig;
+pub use self::revisions::ACTOR_HEADER;
+pub use self::saved_search::spawn_scheduler;
+pub use self::seaart_resp::SeaArtClient;
+pub use self::similar::SimilarIndex;
+pub use self::upstream::Upstreams;
+pub use self::variants::VariantConfig;
+
+use self::albums::{
+    album_image_ids, fetch_album, insert_album, insert_at, list_albums, lock_album, modify_album,
+    remove_album, remove_item, reorder, save_items, unknown_image_ids, Album,
+};
+use self::archive::{parse_ids, write_archive, ARCHIVE_MAX_ITEMS};
+use self::categories::{
+    fetch_category, insert_category, is_ancestor_or_self, is_unique_violation, list_categories,
+    modify_category, remove_category, slugify, Category,
+};
+use self::html_source::HtmlSelectors;
+use self::image_analysis::analyze_download;
+use self::image_source::source_for;
+use self::jobs::{fetch_job, start_job, IngestJob};
+use self::near_duplicate::{duplicate_clusters, DuplicateAction, DuplicateCluster};
+use self::palette::{parse_hex, rgb_to_lab};
+use self::preconditions::{not_modified, row_etag, IfMatch};
+use self::preview::{preview, IngestPreview};
+use self::render::{cache_dir, render, render_etag, render_version, write_cached, RenderParams};
+use self::revisions::{actor, list_revisions, revert_revision, Revision};
+use self::saved_search::{
+    create_saved_search, delete_saved_search, list_runs, list_saved_searches, parse_schedule,
+    SavedSearch, SavedSearchRun,
+};
+use self::tags::{deserialize_tags, query_tags};
+use self::upstream::{UpstreamError, UpstreamStatus};
+use self::variants::{generate_variants, remove_variants, variant_file};
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct CreatePayload {
     image: String,
     ipfs_image_url: String,
@@ -30,6 +114,29 @@ pub struct CreatePayload {
     height: i32,
     prompt: Option<String>,
     hash_id: String,
+    author: Option<AuthorPayload>,
+    model_id: Option<String>,
+    channel: Option<String>,
+    #[serde(default)]
+    num_of_like: i32,
+    #[serde(default)]
+    num_of_collection: i32,
+    #[serde(default)]
+    num_of_view: i32,
+    #[serde(default)]
+    content_rating: ContentRating,
+    #[serde(default)]
+    quarantined: bool,
+    source: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AuthorPayload {
+    id: String,
+    name: String,
+    avatar: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -39,6 +146,137 @@ pub struct UpdatePayload {
     category: Option<String>,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct IngestPayload {
+    source: Option<String>,
+    #[serde(default)]
+    q: String,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    tags: Vec<String>,
+    category: Option<String>,
+    order_by: Option<String>,
+    page: Option<u16>,
+    #[serde(default)]
+    crawl: bool,
+    end_page: Option<u16>,
+    max_items: Option<u32>,
+    delay_ms: Option<u64>,
+    #[serde(default)]
+    resume: bool,
+    url: Option<String>,
+    selectors: Option<HtmlSelectors>,
+    #[serde(default)]
+    download: bool,
+    near_duplicates: Option<DuplicateAction>,
+    near_duplicate_distance: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SavedSearchPayload {
+    #[serde(default)]
+    keyword: String,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    tags: Vec<String>,
+    category: Option<String>,
+    order_by: Option<String>,
+    schedule: String,
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CategoryPayload {
+    name: String,
+    slug: Option<String>,
+    description: Option<String>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CategoryUpdatePayload {
+    name: Option<String>,
+    slug: Option<String>,
+    description: Option<String>,
+    // Slug of the new parent, an empty string makes it a top level category
+    parent: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlbumPayload {
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlbumUpdatePayload {
+    name: Option<String>,
+    description: Option<String>,
+    // Has to be one of the album's images, null removes the cover
+    #[serde(default, deserialize_with = "nullable")]
+    cover_image_id: Option<Option<i32>>,
+}
+
+// Tells a null field, Some(None), from a missing one, None
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlbumItemsPayload {
+    ids: Vec<i32>,
+    // Zero based index to insert at, appended when missing
+    position: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlbumOrderPayload {
+    ids: Vec<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AlbumPage {
+    album: Album,
+    page: usize,
+    per_page: usize,
+    total: usize,
+    items: Vec<ReturnJson>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DuplicatePage {
+    page: usize,
+    per_page: usize,
+    total: usize,
+    items: Vec<DuplicateCluster>,
+}
+
+// Default and cap of the `per_page` query param of /duplicates
+const DUPLICATE_PAGE_SIZE: usize = 20;
+const DUPLICATE_MAX_PAGE_SIZE: usize = 100;
+// Cap of its `distance`, past it every hash clusters with every other
+const DUPLICATE_MAX_DISTANCE: u32 = 16;
+
+// Default and cap of the `per_page` query param of /albums/:id/images
+const ALBUM_PAGE_SIZE: usize = 20;
+const ALBUM_MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Serialize, Debug)]
+pub struct SimilarImage {
+    distance: u32,
+    #[serde(flatten)]
+    image: ReturnJson,
+}
+
+// Defaults and caps of the `distance` and `limit` query params of /images/:id/similar, a wide
+// distance would walk most of the tree
+const SIMILAR_DISTANCE: u32 = 12;
+const SIMILAR_MAX_DISTANCE: u32 = 24;
+const SIMILAR_LIMIT: usize = 20;
+const SIMILAR_MAX_LIMIT: usize = 100;
+
 #[derive(Deserialize, Debug, Serialize)]
 pub struct FormContact {
     name: String,
@@ -47,29 +285,63 @@ pub struct FormContact {
     message: String,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ListResponse {
+    Items(Vec<ReturnJson>),
+    Faceted {
+        items: Vec<ReturnJson>,
+        facets: Vec<TagFacet>,
+    },
+}
+
 pub async fn get_all_ipfs(
+    Query(search_params): Query<Vec<(String, String)>>,
     State(pool): State<Pool<Postgres>>,
-) -> Result<Json<Vec<ReturnJson>>, (StatusCode, String)> {
-    let res = Operation::Fetch
+) -> Result<Json<ListResponse>, (StatusCode, String)> {
+    let filter = list_filter(&search_params)?;
+    let with_facets = search_params
+        .iter()
+        .any(|(key, val)| key == "facets" && val == "true");
+
+    let res = Operation::Search(filter)
         .execute(&pool)
         .await
         .map_err(internal_error);
 
     dbg!(&res);
 
-    match res {
+    let items = match res {
         Ok(response) => match response {
-            ArrStruct(ReturnJsonEnum(data)) => Ok(Json(data)),
-            _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+            ArrStruct(ReturnJsonEnum(data)) => data,
+            _ => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
         },
-        Err(_) => Err((StatusCode::NOT_FOUND, "Shit happen".to_string())),
+        Err(_) => return Err((StatusCode::NOT_FOUND, "Shit happen".to_string())),
+    };
+
+    if !with_facets {
+        return Ok(Json(ListResponse::Items(items)));
+    }
+
+    let ids = items.iter().map(|item| item.id).collect();
+    let res = Operation::TagFacets(ids)
+        .execute(&pool)
+        .await
+        .map_err(internal_error)?;
+
+    match res {
+        Facets(facets) => Ok(Json(ListResponse::Faceted { items, facets })),
+        _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
     }
 }
 
 pub async fn get_all_pretty(
+    Query(search_params): Query<Vec<(String, String)>>,
     State(pool): State<Pool<Postgres>>,
 ) -> Result<String, (StatusCode, String)> {
-    let res = Operation::Fetch
+    let filter = list_filter(&search_params)?;
+
+    let res = Operation::Search(filter)
         .execute(&pool)
         .await
         .map_err(internal_error);
@@ -90,23 +362,119 @@ pub async fn get_all_pretty(
     }
 }
 
+// Default delta E of `color=`, around 2.3 is barely noticeable and 50 is a different hue
+const COLOR_TOLERANCE: f64 = 20.0;
+// Default `min_share=`, the part of the image the matching color has to cover
+const COLOR_MIN_SHARE: f64 = 0.1;
+
+fn list_filter(search_params: &[(String, String)]) -> Result<ListFilter, (StatusCode, String)> {
+    let tags = query_tags(search_params);
+    let search_params: HashMap<&str, &String> = search_params
+        .iter()
+        .map(|(key, val)| (key.as_str(), val))
+        .collect();
+
+    let ratings = match search_params.get("rating") {
+        Some(ratings) => ratings
+            .split(',')
+            .map(|rating| rating.parse::<ContentRating>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        None => Vec::new(),
+    };
+
+    let dimensions_mismatch = search_params
+        .get("dimensions_mismatch")
+        .is_some_and(|val| *val == "true");
+    let near_duplicates = search_params
+        .get("near_duplicates")
+        .is_some_and(|val| *val == "true");
+
+    let min_weight = match search_params.get("min_weight") {
+        Some(weight) => Some(weight.parse::<f64>().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("invalid min_weight `{weight}`"),
+            )
+        })?),
+        None => None,
+    };
+
+    let color = match search_params.get("color") {
+        Some(color) => {
+            let rgb = parse_hex(color).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid color `{color}`, expected #rrggbb"),
+                )
+            })?;
+            let tolerance = match search_params.get("tolerance") {
+                Some(tolerance) => tolerance
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|tolerance| *tolerance >= 0.0)
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!("invalid tolerance `{tolerance}`"),
+                        )
+                    })?,
+                None => COLOR_TOLERANCE,
+            };
+            let min_share = match search_params.get("min_share") {
+                Some(share) => share
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|share| (0.0..=1.0).contains(share))
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!("invalid min_share `{share}`, expected 0 to 1"),
+                        )
+                    })?,
+                None => COLOR_MIN_SHARE,
+            };
+            Some(ColorFilter {
+                lab: rgb_to_lab(rgb),
+                tolerance,
+                min_share,
+            })
+        }
+        None => None,
+    };
+
+    Ok(ListFilter {
+        source: search_params.get("source").cloned().cloned(),
+        category: search_params
+            .get("category")
+            .map(|category| slugify(category).unwrap_or_default()),
+        author_id: search_params.get("author").cloned().cloned(),
+        model_id: search_params.get("model").cloned().cloned(),
+        ratings,
+        include_quarantined: search_params
+            .get("quarantined")
+            .is_some_and(|val| *val == "true"),
+        tags,
+        term: search_params.get("term").cloned().cloned(),
+        min_weight,
+        dimensions_mismatch,
+        near_duplicates,
+        ids: None,
+        sampler: search_params.get("sampler").cloned().cloned(),
+        model_hash: search_params.get("model_hash").cloned().cloned(),
+        color,
+    })
+}
+
 pub async fn create_data(
     State(pool): State<Pool<Postgres>>,
     Json(payload): Json<CreatePayload>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
     dbg!(&payload);
-    let res = Operation::Create {
-        image: payload.image,
-        ipfs_image_url: payload.ipfs_image_url,
-        category: payload.category,
-        width: payload.width,
-        height: payload.height,
-        prompt: payload.prompt,
-        hash_id: payload.hash_id,
-    }
-    .execute(&pool)
-    .await
-    .map_err(internal_error);
+    let res = Operation::Create(payload)
+        .execute(&pool)
+        .await
+        .map_err(internal_error);
 
     match res {
         Ok(resp) => match resp {
@@ -138,39 +506,64 @@ pub async fn contact_form(
 
 pub async fn update_data(
     State(pool): State<Pool<Postgres>>,
+    State(policy): State<Arc<PreconditionPolicy>>,
     Path(id): Path<i32>,
+    headers: HeaderMap,
     Json(payload): Json<UpdatePayload>,
-) -> Result<Json<ReturnJson>, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     dbg!(&payload);
-    let res = Operation::Update(id, payload.image, payload.ipfs_image_url, payload.category)
-        .execute(&pool)
-        .await
-        .map_err(internal_error);
+    let versions = write_versions(&headers, &policy)?;
+    let res = Operation::Update(
+        id,
+        payload.image,
+        payload.ipfs_image_url,
+        payload.category,
+        actor(&headers),
+        versions,
+    )
+    .execute(&pool)
+    .await;
 
     match res {
         Ok(resp) => match resp {
-            UpdateStruct(data) => Ok(Json(data)),
+            UpdateStruct(data) => Ok(with_etag(data)),
             _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
         },
-        Err(_) => Err((StatusCode::NOT_FOUND, "Shit happen".to_string())),
+        Err(sqlx::Error::RowNotFound) => Err(unmatched_write(&pool, id).await),
+        Err(err) => {
+            dbg!(err);
+            Err((StatusCode::NOT_FOUND, "Shit happen".to_string()))
+        }
     }
 }
 
 pub async fn delete_data(
     State(pool): State<Pool<Postgres>>,
+    State(similar): State<Arc<SimilarIndex>>,
+    State(variants): State<Arc<VariantConfig>>,
+    State(policy): State<Arc<PreconditionPolicy>>,
     Path(id): Path<i32>,
+    headers: HeaderMap,
 ) -> Result<Json<Value>, (StatusCode, String)> {
     dbg!(id);
-    let res = Operation::Delete(id)
+    let versions = write_versions(&headers, &policy)?;
+    let res = Operation::Delete(id, versions)
         .execute(&pool)
         .await
         .map_err(internal_error);
 
     match res {
         Ok(resp) => match resp {
-            Deleted(id) => Ok(Json(json!({
-                "message" : format!("{id} successfully deleted")
-            }))),
+            Deleted(0) => Err(unmatched_write(&pool, id).await),
+            Deleted(_) => {
+                similar.remove(id);
+                if let Err(err) = remove_variants(&variants, id).await {
+                    eprintln!("Variants of image {id} were not removed: {err}");
+                }
+                Ok(Json(json!({
+                    "message" : format!("{id} successfully deleted")
+                })))
+            }
             _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
         },
         Err(err) => {
@@ -180,10 +573,47 @@ pub async fn delete_data(
     }
 }
 
+// The versions If-Match allows a write to replace, 428 when the policy wants the header
+fn write_versions(
+    headers: &HeaderMap,
+    policy: &PreconditionPolicy,
+) -> Result<Option<Vec<DateTime<Utc>>>, (StatusCode, String)> {
+    match IfMatch::from_headers(headers) {
+        Some(if_match) => Ok(if_match.versions()),
+        None if policy.require_if_match => Err((
+            StatusCode::PRECONDITION_REQUIRED,
+            "If-Match with the ETag of the row is required".to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+// A conditional write that touched nothing either lost the race or had no row to work on
+async fn unmatched_write(pool: &Pool<Postgres>, id: i32) -> (StatusCode, String) {
+    match Operation::FetchOne(id).execute(pool).await {
+        Ok(Single(row)) => (
+            StatusCode::PRECONDITION_FAILED,
+            format!(
+                "Image {id} changed since it was read, its ETag is now {}",
+                row_etag(row.updated_date.as_deref(), &row)
+            ),
+        ),
+        Ok(_) => (StatusCode::NOT_FOUND, "Imposible".to_string()),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND, "Id not found".to_string()),
+        Err(err) => internal_error(err),
+    }
+}
+
+fn with_etag(row: ReturnJson) -> Response {
+    let etag = row_etag(row.updated_date.as_deref(), &row);
+    ([(ETAG, etag)], Json(row)).into_response()
+}
+
 pub async fn fetch_single(
     State(pool): State<Pool<Postgres>>,
     Path(id): Path<i32>,
-) -> Result<Json<ReturnJson>, (StatusCode, String)> {
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
     dbg!(id);
     let res = Operation::Fetch
         .execute(&pool)
@@ -197,7 +627,13 @@ pub async fn fetch_single(
                 let single_data: Option<ReturnJson> = data.into_iter().find(|s| s.id == id);
 
                 match single_data {
-                    Some(single) => Ok(Json(single)),
+                    Some(single) => {
+                        let etag = row_etag(single.updated_date.as_deref(), &single);
+                        if not_modified(&headers, &etag) {
+                            return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+                        }
+                        Ok(with_etag(single))
+                    }
                     _ => Err((StatusCode::NOT_FOUND, "Id not found".to_string())),
                 }
             }
@@ -207,87 +643,900 @@ pub async fn fetch_single(
     }
 }
 
-pub async fn begin_insert(
-    Query(search_params): Query<HashMap<String, String>>,
+pub async fn get_history(
     State(pool): State<Pool<Postgres>>,
-) -> Result<Json<Value>, (StatusCode, String)> {
-    let q_default = String::from("");
-    let page_default = String::from("1");
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<Revision>>, (StatusCode, String)> {
+    match Operation::FetchOne(id).execute(&pool).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Id not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    }
 
-    let mut tag_vec = Vec::new();
+    let revisions = list_revisions(&pool, id).await.map_err(internal_error)?;
 
-    let q = search_params.get("q").unwrap_or(&q_default);
-    let category = search_params.get("category");
-    let page = search_params.get("page").unwrap_or(&page_default);
-    let tags = search_params.get("tags");
-
-    let page: u16 = page.parse().unwrap_or(1);
-
-    tag_vec.extend(tags);
-
-    match get_raw_value(q, page, tag_vec).await {
-        Ok(result) => {
-            let vec_result = &result["data"]["items"];
-            match vec_result.as_array() {
-                Some(vec_val) => {
-                    let mut count_inserted: u16 = 0;
-                    let mut count_not_inserted: u16 = 0;
-                    for item in vec_val {
-                        let extracted_obj = extract_obj(item);
-
-                        //insert to db;
-
-                        let res = Operation::Create {
-                            image: extracted_obj.0.to_string(),
-                            ipfs_image_url: "NO_IPFS".to_owned(),
-                            category: category.cloned(),
-                            width: extracted_obj.3,
-                            height: extracted_obj.4,
-                            prompt: Some(extracted_obj.2.to_string()),
-                            hash_id: extracted_obj.1.to_string(),
-                        }
-                        .execute(&pool)
-                        .await
-                        .map_err(internal_error);
+    Ok(Json(revisions))
+}
 
-                        match res {
-                            Ok(_) => count_inserted += 1,
-                            Err(_) => count_not_inserted += 1,
-                        }
+// Puts back the values the row had before the revision, recorded as a revision of its own
+pub async fn revert_image(
+    State(pool): State<Pool<Postgres>>,
+    State(policy): State<Arc<PreconditionPolicy>>,
+    Path((id, revision_id)): Path<(i32, i32)>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let versions = write_versions(&headers, &policy)?;
+
+    match revert_revision(
+        &pool,
+        id,
+        revision_id,
+        &actor(&headers),
+        versions.as_deref(),
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err(unmatched_write(&pool, id).await),
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Revision {revision_id} of image {id} not found"),
+            ))
+        }
+        Err(err) => return Err(internal_error(err)),
+    }
+
+    match Operation::FetchOne(id)
+        .execute(&pool)
+        .await
+        .map_err(internal_error)?
+    {
+        Single(data) => Ok(with_etag(data)),
+        _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+    }
+}
+
+pub async fn get_similar(
+    Path(id): Path<i32>,
+    Query(search_params): Query<Vec<(String, String)>>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SimilarImage>>, (StatusCode, String)> {
+    let mut filter = list_filter(&search_params)?;
+    let params: HashMap<&str, &str> = search_params
+        .iter()
+        .map(|(key, val)| (key.as_str(), val.as_str()))
+        .collect();
+    let max_distance =
+        parse_param(&params, "distance", SIMILAR_DISTANCE)?.min(SIMILAR_MAX_DISTANCE);
+    let limit = parse_param(&params, "limit", SIMILAR_LIMIT)?.min(SIMILAR_MAX_LIMIT);
+
+    match Operation::FetchOne(id).execute(&state.pool).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Id not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    }
+
+    // Ask for extra neighbours, the list filter may still hide some of them
+    let Some(neighbours) = state.similar.similar(id, max_distance, limit * 4) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Image has no perceptual hash yet, pin or ingest it with download".to_string(),
+        ));
+    };
+    let distances: HashMap<i32, u32> = neighbours.iter().copied().collect();
+
+    filter.ids = Some(neighbours.iter().map(|(id, _)| *id).collect());
+    let rows = match Operation::Search(filter)
+        .execute(&state.pool)
+        .await
+        .map_err(internal_error)?
+    {
+        ArrStruct(ReturnJsonEnum(rows)) => rows,
+        _ => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+    };
+
+    let mut similar: Vec<SimilarImage> = rows
+        .into_iter()
+        .map(|image| SimilarImage {
+            distance: distances[&image.id],
+            image,
+        })
+        .collect();
+    similar.sort_by_key(|similar| (similar.distance, similar.image.id));
+    similar.truncate(limit);
+
+    Ok(Json(similar))
+}
+
+fn parse_param<T: std::str::FromStr>(
+    params: &HashMap<&str, &str>,
+    key: &str,
+    default: T,
+) -> Result<T, (StatusCode, String)> {
+    match params.get(key) {
+        Some(val) => val
+            .parse()
+            .map_err(|_| (StatusCode::BAD_REQUEST, format!("invalid {key} `{val}`"))),
+        None => Ok(default),
+    }
+}
+
+pub async fn pin_image(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+) -> Result<Json<ReturnJson>, (StatusCode, String)> {
+    if !state.ipfs.is_configured() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "IPFS_STORAGE is not set".to_string(),
+        ));
+    }
+
+    let image = match Operation::FetchOne(id).execute(&state.pool).await {
+        Ok(Single(data)) => data.image,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Id not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
+
+    let image = state
+        .downloader
+        .download(&image)
+        .await
+        .map_err(upstream_error)?;
+
+    analyze_download(&state.pool, &state.similar, id, &image)
+        .await
+        .map_err(|err| match err.downcast_ref::<sqlx::Error>() {
+            Some(_) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+            None => (
+                StatusCode::BAD_GATEWAY,
+                format!("Downloaded file is not a readable image: {err}"),
+            ),
+        })?;
+
+    if let Err(err) = generate_variants(&state.pool, &state.variants, id, &image).await {
+        eprintln!("No variants for image {id}: {err}");
+    }
+
+    let ipfs_image_url = state.ipfs.pin(&image).await.map_err(upstream_error)?;
+
+    let res = Operation::Update(id, None, Some(ipfs_image_url), None, actor(&headers), None)
+        .execute(&state.pool)
+        .await
+        .map_err(internal_error)?;
+
+    match res {
+        UpdateStruct(data) => Ok(Json(data)),
+        _ => Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+    }
+}
+
+pub async fn get_variant(
+    Path((id, file_name)): Path<(i32, String)>,
+    State(pool): State<Pool<Postgres>>,
+) -> Result<Response, (StatusCode, String)> {
+    let (format, path) = variant_file(&pool, id, &file_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Variant not found".to_string()))?;
+
+    let bytes = tokio::fs::read(&path).await.map_err(|err| {
+        eprintln!("Variant {} could not be read: {err}", path.display());
+        (StatusCode::NOT_FOUND, "Variant not found".to_string())
+    })?;
+
+    Ok(([(CONTENT_TYPE, format.content_type())], bytes).into_response())
+}
+
+// A render URL pinned to the current source with `v` never changes, one without it follows
+// the row to new sources and is revalidated with the ETag
+const RENDER_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const RENDER_REVALIDATE: &str = "no-cache";
+
+pub async fn render_image(
+    Path(id): Path<i32>,
+    Query(search_params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let params = RenderParams::from_query(&search_params, state.render.max_size)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let row = match Operation::FetchOne(id).execute(&state.pool).await {
+        Ok(Single(data)) => data,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Id not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
+
+    // Same rule as the variants, a guessed id does not reach restricted images
+    if !row.is_listed() {
+        return Err((StatusCode::NOT_FOUND, "Id not found".to_string()));
+    }
+
+    let version = render_version(&row.image);
+    let etag = render_etag(&version, &params);
+    let cache_control = match search_params.get("v") {
+        Some(v) if *v == version => RENDER_IMMUTABLE,
+        _ => RENDER_REVALIDATE,
+    };
+    let cache_headers = [
+        (ETAG, etag.clone()),
+        (CACHE_CONTROL, cache_control.to_string()),
+        (
+            CONTENT_LOCATION,
+            format!("/images/{id}/render?{}&v={version}", params.query()),
+        ),
+    ];
+
+    if not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let dir = cache_dir(&state.render, id, &row.image);
+    let cached = dir.join(params.file_name());
+
+    let bytes = match tokio::fs::read(&cached).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let original = load_original(&state, &row, &dir, true).await?;
+            let rendered = tokio::task::spawn_blocking(move || render(&original, &params))
+                .await
+                .map_err(internal_error)?
+                .map_err(|err| {
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        format!("Stored file could not be rendered: {err}"),
+                    )
+                })?;
+
+            if state.render.cacheable(&params) {
+                if let Err(err) = write_cached(&cached, &rendered).await {
+                    eprintln!("Render {} was not cached: {err}", cached.display());
+                }
+            }
+            rendered
+        }
+    };
+
+    Ok((
+        cache_headers,
+        [(CONTENT_TYPE, params.format.content_type())],
+        bytes,
+    )
+        .into_response())
+}
+
+// Bytes buffered between the task writing the archive and the response body
+const ARCHIVE_BUFFER: usize = 256 * 1024;
+
+pub async fn download_archive(
+    Query(search_params): Query<Vec<(String, String)>>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let mut filter = list_filter(&search_params)?;
+
+    if let Some((_, ids)) = search_params.iter().find(|(key, _)| key == "ids") {
+        filter.ids = Some(parse_ids(ids).map_err(|err| (StatusCode::BAD_REQUEST, err))?);
+    }
+
+    // Without a selection this would be an export of the whole table
+    if filter.category.is_none() && filter.tags.is_empty() && filter.ids.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "category, tags or ids is required".to_string(),
+        ));
+    }
+
+    let rows = match Operation::Search(filter)
+        .execute(&state.pool)
+        .await
+        .map_err(internal_error)?
+    {
+        ArrStruct(ReturnJsonEnum(rows)) => rows,
+        _ => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+    };
+
+    if rows.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "No images match".to_string()));
+    }
+
+    if rows.len() > ARCHIVE_MAX_ITEMS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} images match, an archive holds at most {ARCHIVE_MAX_ITEMS}",
+                rows.len()
+            ),
+        ));
+    }
+
+    let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER);
+    tokio::spawn(async move {
+        if let Err(err) = write_archive(state, rows, writer).await {
+            eprintln!("Archive stopped: {err}");
+        }
+    });
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/zip"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"images.zip\""),
+        ],
+        StreamBody::new(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+// The original file from the render cache, else from the source URL, else from its IPFS pin.
+// A download is only written to the cache when `cache` is set, renders reuse it but an
+// archive reads every original once
+async fn load_original(
+    state: &AppState,
+    row: &ReturnJson,
+    dir: &std::path::Path,
+    cache: bool,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let path = dir.join("original");
+
+    if let Ok(bytes) = tokio::fs::read(&path).await {
+        return Ok(bytes);
+    }
+
+    let mut urls = vec![row.image.as_str()];
+    if row.ipfs_image_url.starts_with("http") {
+        urls.push(row.ipfs_image_url.as_str());
+    }
+
+    let mut last_err = None;
+    for url in urls {
+        match state.downloader.download(url).await {
+            Ok(image) => {
+                if cache {
+                    if let Err(err) = write_cached(&path, &image.bytes).await {
+                        eprintln!("Original {} was not cached: {err}", path.display());
                     }
-                    dbg!((count_not_inserted, count_inserted));
-                    Ok(Json(json!({
-                        "success": true,
-                        "total_inserted": count_inserted,
-                        "total_failure": count_not_inserted
-                    })))
                 }
-                None => Err((StatusCode::NOT_FOUND, "Value not an array".to_owned())),
+                return Ok(image.bytes.to_vec());
             }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(upstream_error(last_err.unwrap_or_else(|| {
+        eyre::eyre!("image {} has no URL", row.id)
+    })))
+}
+
+fn upstream_error(err: eyre::Report) -> (StatusCode, String) {
+    eprintln!("Upstream call failed: {err}");
+    match err.downcast_ref::<UpstreamError>() {
+        Some(UpstreamError::CircuitOpen { .. }) => {
+            (StatusCode::SERVICE_UNAVAILABLE, err.to_string())
         }
-        Err(err) => Err((StatusCode::BAD_REQUEST, err.to_string())),
+        _ => (StatusCode::BAD_GATEWAY, err.to_string()),
     }
 }
 
-pub async fn test_query(
+pub async fn get_upstreams(State(upstreams): State<Arc<Upstreams>>) -> Json<Vec<UpstreamStatus>> {
+    Json(upstreams.status())
+}
+
+pub async fn get_duplicates(
     Query(search_params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+) -> Result<Json<DuplicatePage>, (StatusCode, String)> {
+    let params: HashMap<&str, &str> = search_params
+        .iter()
+        .map(|(key, val)| (key.as_str(), val.as_str()))
+        .collect();
+    let max_distance = parse_param(&params, "distance", state.duplicate_policy.max_distance)?
+        .min(DUPLICATE_MAX_DISTANCE);
+    let page = parse_param(&params, "page", 1usize)?.max(1);
+    let per_page =
+        parse_param(&params, "per_page", DUPLICATE_PAGE_SIZE)?.clamp(1, DUPLICATE_MAX_PAGE_SIZE);
+
+    let (total, items) =
+        duplicate_clusters(&state.pool, &state.similar, max_distance, page, per_page)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok(Json(DuplicatePage {
+        page,
+        per_page,
+        total,
+        items,
+    }))
+}
+
+pub async fn start_ingest(
+    State(state): State<AppState>,
+    Json(payload): Json<IngestPayload>,
+) -> Result<(StatusCode, Json<Value>), (StatusCode, String)> {
+    source_for(&state, &payload)
+        .and_then(|source| source.search(&payload).map_err(|err| err.to_string()))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let id = start_job(&state, payload).await.map_err(internal_error)?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "job_id": id,
+            "status_url": format!("/ingest/{id}")
+        })),
+    ))
+}
+
+pub async fn preview_ingest(
+    State(state): State<AppState>,
+    Json(payload): Json<IngestPayload>,
+) -> Result<Json<IngestPreview>, (StatusCode, String)> {
+    source_for(&state, &payload)
+        .and_then(|source| source.search(&payload).map_err(|err| err.to_string()))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let preview = preview(&state, &payload).await.map_err(upstream_error)?;
+
+    Ok(Json(preview))
+}
+
+pub async fn ingest_status(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+) -> Result<Json<IngestJob>, (StatusCode, String)> {
+    match fetch_job(&pool, id).await {
+        Ok(job) => Ok(Json(job)),
+        Err(sqlx::Error::RowNotFound) => Err((StatusCode::NOT_FOUND, "Job not found".to_string())),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn cancel_ingest(
+    State(pool): State<Pool<Postgres>>,
+    State(registry): State<Arc<JobRegistry>>,
+    Path(id): Path<i32>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    dbg!(&search_params);
+    let job = match fetch_job(&pool, id).await {
+        Ok(job) => job,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Job not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
 
-    let mut tag_vec: Vec<&String> = Vec::new();
+    if !registry.cancel(job.id) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Job {id} is already {}", job.status),
+        ));
+    }
+
+    Ok(Json(json!({
+        "message": format!("{id} cancellation requested")
+    })))
+}
+
+pub async fn create_search(
+    State(pool): State<Pool<Postgres>>,
+    Json(payload): Json<SavedSearchPayload>,
+) -> Result<Json<SavedSearch>, (StatusCode, String)> {
+    parse_schedule(&payload.schedule).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let saved = create_saved_search(&pool, &payload)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(saved))
+}
+
+pub async fn get_searches(
+    State(pool): State<Pool<Postgres>>,
+) -> Result<Json<Vec<SavedSearch>>, (StatusCode, String)> {
+    let searches = list_saved_searches(&pool).await.map_err(internal_error)?;
+
+    Ok(Json(searches))
+}
+
+pub async fn delete_search(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    match delete_saved_search(&pool, id).await {
+        Ok(0) => Err((StatusCode::NOT_FOUND, "Saved search not found".to_string())),
+        Ok(_) => Ok(Json(json!({
+            "message" : format!("{id} successfully deleted")
+        }))),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn get_search_runs(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<SavedSearchRun>>, (StatusCode, String)> {
+    let runs = list_runs(&pool, id).await.map_err(internal_error)?;
+
+    Ok(Json(runs))
+}
+
+pub async fn get_categories(
+    State(pool): State<Pool<Postgres>>,
+) -> Result<Json<Vec<Category>>, (StatusCode, String)> {
+    let categories = list_categories(&pool).await.map_err(internal_error)?;
+
+    Ok(Json(categories))
+}
+
+pub async fn get_category(
+    State(pool): State<Pool<Postgres>>,
+    Path(slug): Path<String>,
+) -> Result<Json<Category>, (StatusCode, String)> {
+    match fetch_category(&pool, &slug).await {
+        Ok(category) => Ok(Json(category)),
+        Err(sqlx::Error::RowNotFound) => {
+            Err((StatusCode::NOT_FOUND, "Category not found".to_string()))
+        }
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn create_category(
+    State(pool): State<Pool<Postgres>>,
+    Json(payload): Json<CategoryPayload>,
+) -> Result<Json<Category>, (StatusCode, String)> {
+    if payload.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "name is required".to_string()));
+    }
+
+    let slug = category_slug(payload.slug.as_deref().unwrap_or(&payload.name))?;
+    let parent_id = match &payload.parent {
+        Some(parent) => Some(parent_id(&pool, parent).await?),
+        None => None,
+    };
+
+    match insert_category(&pool, &slug, &payload, parent_id).await {
+        Ok(category) => Ok(Json(category)),
+        Err(err) if is_unique_violation(&err) => Err((
+            StatusCode::CONFLICT,
+            format!("Category `{slug}` already exists"),
+        )),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn update_category(
+    State(pool): State<Pool<Postgres>>,
+    Path(slug): Path<String>,
+    Json(payload): Json<CategoryUpdatePayload>,
+) -> Result<Json<Category>, (StatusCode, String)> {
+    let category = match fetch_category(&pool, &slug).await {
+        Ok(category) => category,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, "Category not found".to_string()))
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
+
+    if payload
+        .name
+        .as_deref()
+        .is_some_and(|name| name.trim().is_empty())
+    {
+        return Err((StatusCode::BAD_REQUEST, "name can not be empty".to_string()));
+    }
+
+    let new_slug = match &payload.slug {
+        Some(new_slug) => Some(category_slug(new_slug)?),
+        None => None,
+    };
+
+    let parent_id = match payload.parent.as_deref() {
+        Some("") | None => None,
+        Some(parent) => {
+            let parent_id = parent_id(&pool, parent).await?;
+            if is_ancestor_or_self(&pool, parent_id, category.id())
+                .await
+                .map_err(internal_error)?
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("`{parent}` is `{slug}` or one of its subcategories"),
+                ));
+            }
+            Some(parent_id)
+        }
+    };
+
+    match modify_category(
+        &pool,
+        &slug,
+        new_slug.as_deref(),
+        &payload,
+        payload.parent.is_some(),
+        parent_id,
+    )
+    .await
+    {
+        Ok(category) => Ok(Json(category)),
+        Err(err) if is_unique_violation(&err) => Err((
+            StatusCode::CONFLICT,
+            format!("Category `{}` already exists", new_slug.unwrap_or_default()),
+        )),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn delete_category(
+    State(pool): State<Pool<Postgres>>,
+    Path(slug): Path<String>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    match remove_category(&pool, &slug).await {
+        Ok(0) => Err((StatusCode::NOT_FOUND, "Category not found".to_string())),
+        Ok(_) => Ok(Json(json!({
+            "message" : format!("{slug} successfully deleted")
+        }))),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+fn category_slug(name: &str) -> Result<String, (StatusCode, String)> {
+    slugify(name).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("`{name}` has no letters or digits to build a slug from"),
+        )
+    })
+}
+
+async fn parent_id(pool: &Pool<Postgres>, parent: &str) -> Result<i32, (StatusCode, String)> {
+    match fetch_category(pool, parent).await {
+        Ok(parent) => Ok(parent.id()),
+        Err(sqlx::Error::RowNotFound) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("unknown parent category `{parent}`"),
+        )),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn get_albums(
+    State(pool): State<Pool<Postgres>>,
+) -> Result<Json<Vec<Album>>, (StatusCode, String)> {
+    let albums = list_albums(&pool).await.map_err(internal_error)?;
+
+    Ok(Json(albums))
+}
+
+pub async fn get_album(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    Ok(Json(album_or_404(&pool, id).await?))
+}
+
+pub async fn create_album(
+    State(pool): State<Pool<Postgres>>,
+    Json(payload): Json<AlbumPayload>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    if payload.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "name is required".to_string()));
+    }
+
+    let album = insert_album(&pool, &payload)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(album))
+}
+
+pub async fn update_album(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+    Json(payload): Json<AlbumUpdatePayload>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    if payload
+        .name
+        .as_deref()
+        .is_some_and(|name| name.trim().is_empty())
+    {
+        return Err((StatusCode::BAD_REQUEST, "name can not be empty".to_string()));
+    }
+
+    // The cover is checked against the items under the lock, so a concurrent removal can not
+    // leave it pointing outside the album
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    if !lock_album(&mut tx, id).await.map_err(internal_error)? {
+        return Err((StatusCode::NOT_FOUND, "Album not found".to_string()));
+    }
+
+    if let Some(Some(cover)) = payload.cover_image_id {
+        let ids = album_image_ids(&mut tx, id).await.map_err(internal_error)?;
+        if !ids.contains(&cover) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("image {cover} is not in the album"),
+            ));
+        }
+    }
+
+    modify_album(&mut tx, id, &payload)
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(album_or_404(&pool, id).await?))
+}
+
+pub async fn delete_album(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    match remove_album(&pool, id).await {
+        Ok(0) => Err((StatusCode::NOT_FOUND, "Album not found".to_string())),
+        Ok(_) => Ok(Json(json!({
+            "message" : format!("{id} successfully deleted")
+        }))),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+// The album's images in its order, the usual list filters apply
+pub async fn get_album_images(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+    Query(search_params): Query<Vec<(String, String)>>,
+) -> Result<Json<AlbumPage>, (StatusCode, String)> {
+    let album = album_or_404(&pool, id).await?;
+
+    let mut filter = list_filter(&search_params)?;
+    let params: HashMap<&str, &str> = search_params
+        .iter()
+        .map(|(key, val)| (key.as_str(), val.as_str()))
+        .collect();
+    let page = parse_param(&params, "page", 1usize)?.max(1);
+    let per_page = parse_param(&params, "per_page", ALBUM_PAGE_SIZE)?.clamp(1, ALBUM_MAX_PAGE_SIZE);
+
+    let ids = album_image_ids(&pool, id).await.map_err(internal_error)?;
+    let positions: HashMap<i32, usize> = ids
+        .iter()
+        .enumerate()
+        .map(|(position, id)| (*id, position))
+        .collect();
+
+    filter.ids = Some(ids);
+    let mut rows = match Operation::Search(filter)
+        .execute(&pool)
+        .await
+        .map_err(internal_error)?
+    {
+        ArrStruct(ReturnJsonEnum(rows)) => rows,
+        _ => return Err((StatusCode::NOT_FOUND, "Imposible".to_string())),
+    };
+    rows.sort_by_key(|row| positions[&row.id]);
+
+    let total = rows.len();
+    let items = rows
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .collect();
+
+    Ok(Json(AlbumPage {
+        album,
+        page,
+        per_page,
+        total,
+        items,
+    }))
+}
+
+pub async fn add_album_images(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+    Json(payload): Json<AlbumItemsPayload>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    album_or_404(&pool, id).await?;
+
+    let unknown = unknown_image_ids(&pool, &payload.ids)
+        .await
+        .map_err(internal_error)?;
+    if !unknown.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("unknown image ids {unknown:?}"),
+        ));
+    }
+
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    if !lock_album(&mut tx, id).await.map_err(internal_error)? {
+        return Err((StatusCode::NOT_FOUND, "Album not found".to_string()));
+    }
+
+    let existing = album_image_ids(&mut tx, id).await.map_err(internal_error)?;
+    let ordered = insert_at(&existing, &payload.ids, payload.position);
+    save_items(&mut tx, id, &ordered)
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(album_or_404(&pool, id).await?))
+}
+
+pub async fn remove_album_image(
+    State(pool): State<Pool<Postgres>>,
+    Path((id, image_id)): Path<(i32, i32)>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    album_or_404(&pool, id).await?;
+
+    match remove_item(&pool, id, image_id).await {
+        Ok(0) => Err((
+            StatusCode::NOT_FOUND,
+            format!("image {image_id} is not in the album"),
+        )),
+        Ok(_) => Ok(Json(album_or_404(&pool, id).await?)),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn reorder_album(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i32>,
+    Json(payload): Json<AlbumOrderPayload>,
+) -> Result<Json<Album>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    if !lock_album(&mut tx, id).await.map_err(internal_error)? {
+        return Err((StatusCode::NOT_FOUND, "Album not found".to_string()));
+    }
+
+    let existing = album_image_ids(&mut tx, id).await.map_err(internal_error)?;
+    let ordered = reorder(&existing, &payload.ids).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    save_items(&mut tx, id, &ordered)
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(album_or_404(&pool, id).await?))
+}
+
+async fn album_or_404(pool: &Pool<Postgres>, id: i32) -> Result<Album, (StatusCode, String)> {
+    match fetch_album(pool, id).await {
+        Ok(album) => Ok(album),
+        Err(sqlx::Error::RowNotFound) => {
+            Err((StatusCode::NOT_FOUND, "Album not found".to_string()))
+        }
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+pub async fn test_query(
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    dbg!(&params);
+
+    let tag_vec = query_tags(&params);
+    let search_params: HashMap<String, String> = params.into_iter().collect();
 
     let empty_str = String::from("");
     let page_default = String::from("1");
 
     let q = search_params.get("q").unwrap_or(&empty_str);
     let category = search_params.get("category");
-    let tags = search_params.get("tags");
     let page = search_params.get("page").unwrap_or(&page_default);
 
     let page = page.parse::<u16>().unwrap_or(1);
 
-    tag_vec.extend(tags);
-
     Ok(Json(json!({
         "q": q,
         "category": category,
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use sqlx::{PgExecutor, Pool, Postgres, Transaction};
+
+use super::{ipfs_model::datetime_to_string, AlbumPayload, AlbumUpdatePayload};
+
+#[derive(Serialize, Debug)]
+pub struct Album {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    cover_image_id: Option<i32>,
+    item_count: i64,
+    created: Option<String>,
+    updated_date: Option<String>,
+}
+
+// `new` ids not in the album yet, inserted at `position` or appended, the others keep their order
+pub fn insert_at(existing: &[i32], new: &[i32], position: Option<usize>) -> Vec<i32> {
+    let mut seen: HashSet<i32> = existing.iter().copied().collect();
+    let added: Vec<i32> = new.iter().copied().filter(|id| seen.insert(*id)).collect();
+
+    let position = position.unwrap_or(existing.len()).min(existing.len());
+    let mut ordered = existing.to_vec();
+    ordered.splice(position..position, added);
+
+    ordered
+}
+
+// A reorder has to list every item of the album exactly once
+pub fn reorder(existing: &[i32], requested: &[i32]) -> Result<Vec<i32>, String> {
+    let existing: HashSet<i32> = existing.iter().copied().collect();
+    let mut seen = HashSet::new();
+
+    for id in requested {
+        if !existing.contains(id) {
+            return Err(format!("image {id} is not in the album"));
+        }
+        if !seen.insert(*id) {
+            return Err(format!("image {id} is listed twice"));
+        }
+    }
+
+    if let Some(missing) = existing.iter().find(|id| !seen.contains(id)) {
+        return Err(format!("image {missing} is missing from the new order"));
+    }
+
+    Ok(requested.to_vec())
+}
+
+// Every album, or only `id`, with its item count
+async fn query_albums(pool: &Pool<Postgres>, id: Option<i32>) -> Result<Vec<Album>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            a.id, a.name, a.description, a.cover_image_id, a.time_created, a.updated_date,
+            (SELECT COUNT(*) FROM album_items i WHERE i.album_id = a.id) AS "item_count!"
+        FROM albums a
+        WHERE $1::INT IS NULL OR a.id = $1
+        ORDER BY a.id
+        "#,
+        id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let albums = rows
+        .into_iter()
+        .map(|row| Album {
+            id: row.id,
+            name: row.name,
+            description: row.description,
+            cover_image_id: row.cover_image_id,
+            item_count: row.item_count,
+            created: datetime_to_string(row.time_created),
+            updated_date: datetime_to_string(row.updated_date),
+        })
+        .collect();
+
+    Ok(albums)
+}
+
+pub async fn list_albums(pool: &Pool<Postgres>) -> Result<Vec<Album>, sqlx::Error> {
+    query_albums(pool, None).await
+}
+
+pub async fn fetch_album(pool: &Pool<Postgres>, id: i32) -> Result<Album, sqlx::Error> {
+    query_albums(pool, Some(id))
+        .await?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn insert_album(
+    pool: &Pool<Postgres>,
+    payload: &AlbumPayload,
+) -> Result<Album, sqlx::Error> {
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO albums (name, description)
+        VALUES ($1, $2)
+        RETURNING id
+        "#,
+        payload.name.trim(),
+        payload.description.as_deref(),
+    )
+    .fetch_one(pool)
+    .await?;
+
+    fetch_album(pool, id).await
+}
+
+// `cover_image_id` is only touched when the payload has it, so it can be cleared as well as changed
+pub async fn modify_album(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    payload: &AlbumUpdatePayload,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE albums
+        SET
+            name = COALESCE($2, name),
+            description = COALESCE($3, description),
+            cover_image_id = CASE WHEN $4 THEN $5 ELSE cover_image_id END,
+            updated_date = NOW()
+        WHERE id = $1
+        "#,
+        id,
+        payload.name.as_deref().map(str::trim),
+        payload.description.as_deref(),
+        payload.cover_image_id.is_some(),
+        payload.cover_image_id.flatten(),
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn remove_album(pool: &Pool<Postgres>, id: i32) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM albums
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+// Image ids of the album in display order
+pub async fn album_image_ids(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT image_id
+        FROM album_items
+        WHERE album_id = $1
+        ORDER BY position, time_created
+        "#,
+        id,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+// Holds the album row until the transaction ends, so concurrent edits of its items queue up
+// behind each other instead of saving from the same stale read. False when there is no album.
+pub async fn lock_album(tx: &mut Transaction<'_, Postgres>, id: i32) -> Result<bool, sqlx::Error> {
+    let locked = sqlx::query_scalar!("SELECT id FROM albums WHERE id = $1 FOR UPDATE", id)
+        .fetch_optional(tx)
+        .await?;
+
+    Ok(locked.is_some())
+}
+
+// Ids of `ids` that are not rows of ipfs_image
+pub async fn unknown_image_ids(
+    pool: &Pool<Postgres>,
+    ids: &[i32],
+) -> Result<Vec<i32>, sqlx::Error> {
+    let known: HashSet<i32> = sqlx::query_scalar!(
+        r#"
+        SELECT id
+        FROM ipfs_image
+        WHERE id = ANY($1)
+        "#,
+        ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+
+    Ok(ids
+        .iter()
+        .copied()
+        .filter(|id| !known.contains(id))
+        .collect())
+}
+
+// Adds the missing items of `ordered` and stores its order as the positions, in the
+// transaction that locked the album and read the order `ordered` was built from
+pub async fn save_items(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    ordered: &[i32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO album_items (album_id, image_id, position)
+        SELECT $1, image_id, position - 1
+        FROM UNNEST($2::INT[]) WITH ORDINALITY AS items (image_id, position)
+        ON CONFLICT (album_id, image_id) DO UPDATE SET position = EXCLUDED.position
+        "#,
+        id,
+        ordered,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    touch_album(tx, id).await
+}
+
+pub async fn remove_item(
+    pool: &Pool<Postgres>,
+    id: i32,
+    image_id: i32,
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if !lock_album(&mut tx, id).await? {
+        return Ok(0);
+    }
+
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM album_items
+        WHERE album_id = $1 AND image_id = $2
+        "#,
+        id,
+        image_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    // An image that left the album can not stay its cover
+    sqlx::query!(
+        r#"
+        UPDATE albums
+        SET cover_image_id = NULL
+        WHERE id = $1 AND cover_image_id = $2
+        "#,
+        id,
+        image_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    touch_album(&mut tx, id).await?;
+    tx.commit().await?;
+
+    Ok(res.rows_affected())
+}
+
+async fn touch_album(tx: &mut Transaction<'_, Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE albums
+        SET updated_date = NOW()
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order_test() {
+        assert_eq!(vec![1, 2, 3, 4], insert_at(&[1, 2], &[3, 4], None));
+        assert_eq!(vec![1, 5, 2], insert_at(&[1, 2], &[5, 1], Some(1)));
+        assert_eq!(vec![7, 1, 2], insert_at(&[1, 2], &[7, 7], Some(0)));
+        assert_eq!(vec![1, 2, 9], insert_at(&[1, 2], &[9], Some(10)));
+
+        assert_eq!(Ok(vec![3, 1, 2]), reorder(&[1, 2, 3], &[3, 1, 2]));
+        assert!(reorder(&[1, 2, 3], &[3, 1]).is_err());
+        assert!(reorder(&[1, 2, 3], &[3, 1, 1, 2]).is_err());
+        assert!(reorder(&[1, 2, 3], &[3, 1, 2, 4]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipDateTime, ZipEntryBuilder};
+use serde::Serialize;
+use sqlx::types::chrono::Utc;
+use tokio::io::DuplexStream;
+
+use super::{ipfs_model::ReturnJson, load_original, render::cache_dir};
+use crate::AppState;
+
+// Rows a single archive may hold, larger exports have to be split by the caller
+pub const ARCHIVE_MAX_ITEMS: usize = 500;
+
+// One row of `manifest.json`, `file` is None when the original could not be fetched
+#[derive(Serialize, Debug)]
+pub struct ManifestItem {
+    file: Option<String>,
+    error: Option<String>,
+    #[serde(flatten)]
+    image: ReturnJson,
+}
+
+// `ids=1,2,3`
+pub fn parse_ids(ids: &str) -> Result<Vec<i32>, String> {
+    ids.split(',')
+        .filter(|id| !id.trim().is_empty())
+        .map(|id| {
+            id.trim()
+                .parse::<i32>()
+                .map_err(|_| format!("invalid id `{id}`"))
+        })
+        .collect()
+}
+
+// `{id}.{ext}` with the extension of the actual file, the source URL may not have one
+pub fn entry_name(id: i32, bytes: &[u8]) -> String {
+    let ext = image::guess_format(bytes)
+        .ok()
+        .and_then(|format| format.extensions_str().first())
+        .unwrap_or(&"bin");
+
+    format!("{id}.{ext}")
+}
+
+// Writes one stored entry per original, then the manifest, fetching a single original at a
+// time so only the file in flight is held in memory. Stops as soon as the reader goes away.
+pub async fn write_archive(
+    state: AppState,
+    rows: Vec<ReturnJson>,
+    writer: DuplexStream,
+) -> async_zip::error::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut manifest = Vec::with_capacity(rows.len());
+    let now = ZipDateTime::from_chrono(&Utc::now());
+
+    for row in rows {
+        let dir = cache_dir(&state.render, row.id, &row.image);
+
+        match load_original(&state, &row, &dir, false).await {
+            Ok(bytes) => {
+                let file = entry_name(row.id, &bytes);
+                // Images are already compressed, deflating them again only costs CPU
+                let entry = ZipEntryBuilder::new(file.clone().into(), Compression::Stored)
+                    .last_modification_date(now);
+                zip.write_entry_whole(entry, &bytes).await?;
+
+                manifest.push(ManifestItem {
+                    file: Some(file),
+                    error: None,
+                    image: row,
+                });
+            }
+            Err((_, err)) => manifest.push(ManifestItem {
+                file: None,
+                error: Some(err),
+                image: row,
+            }),
+        }
+    }
+
+    let manifest = serde_json::to_vec_pretty(&manifest).unwrap_or_default();
+    let entry = ZipEntryBuilder::new("manifest.json".to_string().into(), Compression::Stored)
+        .last_modification_date(now);
+    zip.write_entry_whole(entry, &manifest).await?;
+    zip.close().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn archive_test() {
+        assert_eq!(Ok(vec![1, 2, 30]), parse_ids("1, 2,,30"));
+        assert!(parse_ids("1,two").is_err());
+
+        assert_eq!("7.png", entry_name(7, b"\x89PNG\r\n\x1a\n"));
+        assert_eq!("8.jpg", entry_name(8, &[0xff, 0xd8, 0xff, 0xe0]));
+        assert_eq!("9.bin", entry_name(9, b"not an image"));
+    }
+}
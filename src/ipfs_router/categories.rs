@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::{PgExecutor, Pool, Postgres};
+
+use super::{ipfs_model::datetime_to_string, CategoryPayload, CategoryUpdatePayload};
+
+#[derive(Serialize, Debug)]
+pub struct Category {
+    id: i32,
+    slug: String,
+    name: String,
+    description: Option<String>,
+    parent: Option<String>,
+    // Images filed directly under the category, and including its subcategories
+    image_count: i64,
+    total_count: i64,
+    created: Option<String>,
+    updated_date: Option<String>,
+}
+
+impl Category {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+// `Sci Fi`, ` sci-fi ` and `SCI_FI` all become `sci-fi`, None when nothing is left
+pub fn slugify(name: &str) -> Option<String> {
+    let mut slug = String::new();
+
+    for c in name.trim().chars() {
+        if c.is_alphanumeric() {
+            // Some lowercase forms carry combining marks, dropping them keeps slugify stable
+            slug.extend(c.to_lowercase().filter(|c| c.is_alphanumeric()));
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    Some(slug.to_string()).filter(|slug| !slug.is_empty())
+}
+
+// A stored category with the number of images filed under it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CategoryUse {
+    pub id: i32,
+    pub slug: String,
+    pub name: String,
+    pub images: i64,
+}
+
+// Stored categories that share a slugify slug, kept as one category
+#[derive(Debug, PartialEq)]
+pub struct CategoryMerge {
+    pub slug: String,
+    // The row already holding `slug` if there is one, so nothing else has to move it
+    pub keep: CategoryUse,
+    pub name: String,
+    pub merged: Vec<CategoryUse>,
+}
+
+// Groups categories whose slug is not what slugify makes of it, spellings stored before
+// categories existed, with the others sharing that slug. The most used spelling of a group,
+// then the first slug, names the merged category. A slug slugify rejects is left alone.
+pub fn plan_normalization(categories: Vec<CategoryUse>) -> Vec<CategoryMerge> {
+    let mut groups: BTreeMap<String, Vec<CategoryUse>> = BTreeMap::new();
+
+    for category in categories {
+        if let Some(slug) = slugify(&category.slug) {
+            groups.entry(slug).or_default().push(category);
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|(slug, group)| group.iter().any(|category| category.slug != *slug))
+        .map(|(slug, mut group)| {
+            group.sort_by(|a, b| b.images.cmp(&a.images).then_with(|| a.slug.cmp(&b.slug)));
+            let name = group[0].name.clone();
+            let keep = group
+                .iter()
+                .position(|category| category.slug == slug)
+                .unwrap_or(0);
+            let keep = group.remove(keep);
+
+            CategoryMerge {
+                slug,
+                keep,
+                name,
+                merged: group,
+            }
+        })
+        .collect()
+}
+
+// Applies plan_normalization on startup, returns how many stored categories changed slug
+pub async fn normalize_categories(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let categories = sqlx::query!(
+        r#"
+        SELECT categories.id, categories.slug, categories.name, COUNT(ipfs_image.id) AS "images!"
+        FROM categories
+        LEFT JOIN ipfs_image ON ipfs_image.category = categories.slug
+        GROUP BY categories.id
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CategoryUse {
+        id: row.id,
+        slug: row.slug,
+        name: row.name,
+        images: row.images,
+    })
+    .collect();
+
+    let mut normalized = 0;
+
+    for merge in plan_normalization(categories) {
+        let mut tx = pool.begin().await?;
+
+        // ON UPDATE CASCADE moves the images along when the kept row is renamed
+        sqlx::query!(
+            r#"
+            UPDATE categories
+            SET slug = $2, name = $3, updated_date = NOW()
+            WHERE id = $1
+            "#,
+            merge.keep.id,
+            merge.slug,
+            merge.name,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        for category in &merge.merged {
+            sqlx::query!(
+                "UPDATE ipfs_image SET category = $2 WHERE category = $1",
+                category.slug,
+                merge.slug
+            )
+            .execute(&mut tx)
+            .await?;
+            sqlx::query!("DELETE FROM categories WHERE id = $1", category.id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        normalized += merge.merged.len() as u64 + u64::from(merge.keep.slug != merge.slug);
+    }
+
+    Ok(normalized)
+}
+
+// Files rows under the slug of `category`, creating it on first use like authors and tags
+pub async fn ensure_category(
+    executor: impl PgExecutor<'_>,
+    category: Option<&str>,
+) -> Result<Option<String>, sqlx::Error> {
+    let Some(slug) = category.and_then(slugify) else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO categories (slug, name)
+        VALUES ($1, $2)
+        ON CONFLICT (slug) DO NOTHING
+        "#,
+        slug,
+        category.unwrap_or_default().trim(),
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(Some(slug))
+}
+
+// Every category, or only `slug`, with its image counts
+async fn query_categories(
+    pool: &Pool<Postgres>,
+    slug: Option<&str>,
+) -> Result<Vec<Category>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        WITH RECURSIVE tree (root, id) AS (
+            SELECT id, id FROM categories
+            UNION
+            SELECT tree.root, child.id
+            FROM categories child
+            JOIN tree ON child.parent_id = tree.id
+        ),
+        counts AS (
+            SELECT category, COUNT(*) AS images
+            FROM ipfs_image
+            WHERE category IS NOT NULL
+            GROUP BY category
+        )
+        SELECT
+            c.id, c.slug, c.name, c.description, parent.slug AS "parent?",
+            COALESCE((SELECT images FROM counts WHERE counts.category = c.slug), 0)
+                AS "image_count!",
+            COALESCE((
+                SELECT SUM(counts.images)
+                FROM tree
+                JOIN categories descendant ON descendant.id = tree.id
+                JOIN counts ON counts.category = descendant.slug
+                WHERE tree.root = c.id
+            ), 0)::BIGINT AS "total_count!",
+            c.time_created, c.updated_date
+        FROM categories c
+        LEFT JOIN categories parent ON parent.id = c.parent_id
+        WHERE $1::TEXT IS NULL OR c.slug = $1
+        ORDER BY c.slug
+        "#,
+        slug,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let categories = rows
+        .into_iter()
+        .map(|row| Category {
+            id: row.id,
+            slug: row.slug,
+            name: row.name,
+            description: row.description,
+            parent: row.parent,
+            image_count: row.image_count,
+            total_count: row.total_count,
+            created: datetime_to_string(row.time_created),
+            updated_date: datetime_to_string(row.updated_date),
+        })
+        .collect();
+
+    Ok(categories)
+}
+
+pub async fn list_categories(pool: &Pool<Postgres>) -> Result<Vec<Category>, sqlx::Error> {
+    query_categories(pool, None).await
+}
+
+pub async fn fetch_category(pool: &Pool<Postgres>, slug: &str) -> Result<Category, sqlx::Error> {
+    query_categories(pool, Some(slug))
+        .await?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn insert_category(
+    pool: &Pool<Postgres>,
+    slug: &str,
+    payload: &CategoryPayload,
+    parent_id: Option<i32>,
+) -> Result<Category, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO categories (slug, name, description, parent_id)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        slug,
+        payload.name.trim(),
+        payload.description.as_deref(),
+        parent_id,
+    )
+    .execute(pool)
+    .await?;
+
+    fetch_category(pool, slug).await
+}
+
+// `parent` is only touched when `set_parent` is, so it can be cleared as well as changed
+pub async fn modify_category(
+    pool: &Pool<Postgres>,
+    slug: &str,
+    new_slug: Option<&str>,
+    payload: &CategoryUpdatePayload,
+    set_parent: bool,
+    parent_id: Option<i32>,
+) -> Result<Category, sqlx::Error> {
+    let slug = sqlx::query_scalar!(
+        r#"
+        UPDATE categories
+        SET
+            slug = COALESCE($2, slug),
+            name = COALESCE($3, name),
+            description = COALESCE($4, description),
+            parent_id = CASE WHEN $5 THEN $6 ELSE parent_id END,
+            updated_date = NOW()
+        WHERE slug = $1
+        RETURNING slug
+        "#,
+        slug,
+        new_slug,
+        payload.name.as_deref().map(str::trim),
+        payload.description.as_deref(),
+        set_parent,
+        parent_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    fetch_category(pool, &slug).await
+}
+
+pub async fn remove_category(pool: &Pool<Postgres>, slug: &str) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM categories
+        WHERE slug = $1
+        "#,
+        slug,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+// True when `ancestor` is `id` itself or one of its parents, used to refuse parent cycles
+pub async fn is_ancestor_or_self(
+    pool: &Pool<Postgres>,
+    id: i32,
+    ancestor: i32,
+) -> Result<bool, sqlx::Error> {
+    let found = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors (id, parent_id) AS (
+            SELECT id, parent_id FROM categories WHERE id = $1
+            UNION
+            SELECT parent.id, parent.parent_id
+            FROM categories parent
+            JOIN ancestors ON ancestors.parent_id = parent.id
+        )
+        SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2) AS "found!"
+        "#,
+        id,
+        ancestor,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(found)
+}
+
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Database(err) if err.code().as_deref() == Some("23505"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slugify_test() {
+        assert_eq!(Some("anime".to_string()), slugify(" Anime "));
+        assert_eq!(Some("sci-fi".to_string()), slugify("Sci Fi"));
+        assert_eq!(Some("sci-fi".to_string()), slugify("SCI__fi!"));
+        assert_eq!(Some("猫耳".to_string()), slugify("猫耳"));
+        assert_eq!(None, slugify(" --- "));
+        assert_eq!(Some("猫耳-café".to_string()), slugify("猫耳 Café"));
+
+        for name in ["İstanbul", "Straße", "猫耳 Café", "Sci Fi"] {
+            let slug = slugify(name).unwrap();
+            assert_eq!(Some(slug.clone()), slugify(&slug), "{name}");
+        }
+    }
+
+    #[test]
+    fn plan_normalization_test() {
+        let category = |id, slug: &str, images| CategoryUse {
+            id,
+            slug: slug.to_string(),
+            name: slug.to_string(),
+            images,
+        };
+
+        // Two spellings of the same slug, neither stored under it yet
+        let plan = plan_normalization(vec![
+            category(1, "Sci Fi", 2),
+            category(2, "SCI_FI", 5),
+            category(3, "anime", 1),
+            category(4, " --- ", 9),
+        ]);
+        assert_eq!(
+            vec![CategoryMerge {
+                slug: "sci-fi".to_string(),
+                keep: category(2, "SCI_FI", 5),
+                name: "SCI_FI".to_string(),
+                merged: vec![category(1, "Sci Fi", 2)],
+            }],
+            plan
+        );
+
+        // The row already holding the slug is kept, the most used spelling still names it
+        let plan = plan_normalization(vec![category(1, "anime", 1), category(2, "Anime", 3)]);
+        assert_eq!(
+            vec![CategoryMerge {
+                slug: "anime".to_string(),
+                keep: category(1, "anime", 1),
+                name: "Anime".to_string(),
+                merged: vec![category(2, "Anime", 3)],
+            }],
+            plan
+        );
+
+        // Equal use falls back to the first slug
+        let plan = plan_normalization(vec![category(1, "Sci Fi", 1), category(2, "SCI FI", 1)]);
+        assert_eq!("SCI FI", plan[0].name);
+    }
+}
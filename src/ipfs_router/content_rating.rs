@@ -0,0 +1,197 @@
+use std::{env, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentRating {
+    Safe,
+    Sensitive,
+    Explicit,
+    // Sources without any rating information, such as scraped pages, and anything else
+    // nothing rated: an unrated row stays out of the default listing
+    #[default]
+    Unrated,
+}
+
+impl ContentRating {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Safe => "safe",
+            Self::Sensitive => "sensitive",
+            Self::Explicit => "explicit",
+            Self::Unrated => "unrated",
+        }
+    }
+}
+
+impl FromStr for ContentRating {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "safe" => Ok(Self::Safe),
+            "sensitive" => Ok(Self::Sensitive),
+            "explicit" => Ok(Self::Explicit),
+            "unrated" => Ok(Self::Unrated),
+            other => Err(format!("unknown content rating `{other}`")),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingAction {
+    Allow,
+    Quarantine,
+    Skip,
+}
+
+impl FromStr for RatingAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "quarantine" => Ok(Self::Quarantine),
+            "skip" => Ok(Self::Skip),
+            other => Err(format!("unknown rating action `{other}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestPolicy {
+    pub safe: RatingAction,
+    pub sensitive: RatingAction,
+    pub explicit: RatingAction,
+    pub unrated: RatingAction,
+}
+
+impl Default for IngestPolicy {
+    fn default() -> Self {
+        IngestPolicy {
+            safe: RatingAction::Allow,
+            sensitive: RatingAction::Quarantine,
+            explicit: RatingAction::Skip,
+            unrated: RatingAction::Quarantine,
+        }
+    }
+}
+
+impl FromStr for IngestPolicy {
+    type Err = String;
+
+    // Format: `safe=allow,sensitive=quarantine,explicit=skip,unrated=quarantine`, missing ratings
+    // keep their default
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = IngestPolicy::default();
+
+        for rule in s.split(',').filter(|rule| !rule.trim().is_empty()) {
+            let (rating, action) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("invalid policy rule `{rule}`"))?;
+
+            let action: RatingAction = action.parse()?;
+            match rating.parse()? {
+                ContentRating::Safe => policy.safe = action,
+                ContentRating::Sensitive => policy.sensitive = action,
+                ContentRating::Explicit => policy.explicit = action,
+                ContentRating::Unrated => policy.unrated = action,
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+impl IngestPolicy {
+    pub fn from_env() -> Self {
+        match env::var("NSFW_POLICY") {
+            Ok(policy) => policy.parse().expect("NSFW_POLICY is invalid"),
+            Err(_) => IngestPolicy::default(),
+        }
+    }
+
+    pub fn action_for(&self, rating: ContentRating) -> RatingAction {
+        match rating {
+            ContentRating::Safe => self.safe,
+            ContentRating::Sensitive => self.sensitive,
+            ContentRating::Explicit => self.explicit,
+            ContentRating::Unrated => self.unrated,
+        }
+    }
+}
+
+// SeaArt marks adult content with `nsfw`/`banner.nsfw` >= 2 and hardcore content with
+// `is_nsfw_plus`, `green` is 1 once an artwork passed their safe-for-work review. Items
+// without any usable flag are unrated rather than safe.
+pub fn rate_item(val: &Value) -> ContentRating {
+    let nsfw = val["nsfw"]
+        .as_i64()
+        .into_iter()
+        .chain(val["banner"]["nsfw"].as_i64())
+        .max();
+    let nsfw_plus = val["is_nsfw_plus"].as_bool().unwrap_or_default()
+        || val["banner"]["is_nsfw_plus"].as_bool().unwrap_or_default();
+    let green = val["green"].as_i64();
+
+    if nsfw_plus || nsfw >= Some(3) {
+        ContentRating::Explicit
+    } else if nsfw >= Some(2) || green >= Some(2) {
+        ContentRating::Sensitive
+    } else if nsfw.is_none() && green.is_none() {
+        ContentRating::Unrated
+    } else {
+        ContentRating::Safe
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rate_test() {
+        let safe = json!({ "nsfw": 1, "banner": { "nsfw": 1, "is_nsfw_plus": false }, "green": 1 });
+        let sensitive =
+            json!({ "nsfw": 2, "banner": { "nsfw": 2, "is_nsfw_plus": false }, "green": 2 });
+        let explicit =
+            json!({ "nsfw": 2, "banner": { "nsfw": 2, "is_nsfw_plus": true }, "green": 2 });
+
+        assert_eq!(ContentRating::Safe, rate_item(&safe));
+        assert_eq!(ContentRating::Sensitive, rate_item(&sensitive));
+        assert_eq!(ContentRating::Explicit, rate_item(&explicit));
+
+        // Missing or malformed flags never pass as safe
+        assert_eq!(ContentRating::Unrated, rate_item(&json!({})));
+        assert_eq!(
+            ContentRating::Unrated,
+            rate_item(&json!({ "nsfw": "1", "banner": { "url": "a.png" } }))
+        );
+        assert_eq!(ContentRating::Safe, rate_item(&json!({ "green": 1 })));
+        assert_eq!(ContentRating::Unrated, ContentRating::default());
+    }
+
+    #[test]
+    fn policy_test() {
+        let policy: IngestPolicy = "sensitive=allow, explicit=quarantine".parse().unwrap();
+
+        assert_eq!(RatingAction::Allow, policy.action_for(ContentRating::Safe));
+        assert_eq!(
+            RatingAction::Allow,
+            policy.action_for(ContentRating::Sensitive)
+        );
+        assert_eq!(
+            RatingAction::Quarantine,
+            policy.action_for(ContentRating::Explicit)
+        );
+        assert_eq!(
+            RatingAction::Quarantine,
+            policy.action_for(ContentRating::Unrated)
+        );
+        assert!("explicit=delete".parse::<IngestPolicy>().is_err());
+    }
+}
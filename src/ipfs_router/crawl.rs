@@ -0,0 +1,697 @@
+use std::{collections::HashSet, time::Duration};
+
+use async_trait::async_trait;
+use eyre::{eyre, Result};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+
+use crate::AppState;
+
+use super::{
+    content_rating::RatingAction,
+    image_analysis::{inspect_download, store_analysis, ImageFacts},
+    image_download::DownloadedImage,
+    image_source::{source_for, ImageSource},
+    ipfs_model::{Operation, OperationResult},
+    jobs::JobHandle,
+    near_duplicate::{DuplicateAction, DuplicatePolicy},
+    tags::normalize_tags,
+    variants::generate_variants,
+    CreatePayload, IngestPayload,
+};
+
+// Upper bound for a crawl without an explicit end_page
+const DEFAULT_MAX_PAGES: u16 = 50;
+
+#[derive(Debug)]
+pub struct CrawlOptions {
+    pub start_page: u16,
+    pub end_page: Option<u16>,
+    pub max_items: Option<u32>,
+    pub delay: Duration,
+    pub resume: bool,
+}
+
+impl CrawlOptions {
+    pub fn single_page(page: u16) -> Self {
+        CrawlOptions {
+            start_page: page,
+            end_page: Some(page),
+            max_items: None,
+            delay: Duration::ZERO,
+            resume: false,
+        }
+    }
+}
+
+impl From<&IngestPayload> for CrawlOptions {
+    fn from(payload: &IngestPayload) -> Self {
+        let page = payload.page.unwrap_or(1);
+
+        if !payload.crawl {
+            return CrawlOptions::single_page(page);
+        }
+
+        CrawlOptions {
+            start_page: page,
+            end_page: payload.end_page,
+            max_items: payload.max_items,
+            delay: Duration::from_millis(payload.delay_ms.unwrap_or(1000)),
+            resume: payload.resume,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CrawlReport {
+    pub first_page: u16,
+    pub last_page: u16,
+    pub pages_crawled: u16,
+    pub total_inserted: u32,
+    pub total_duplicate: u32,
+    pub total_failure: u32,
+    pub total_skipped: u32,
+    pub total_quarantined: u32,
+    pub total_near_duplicate: u32,
+    pub stop_reason: &'static str,
+}
+
+#[derive(Debug, Default)]
+pub struct PageOutcome {
+    pub inserted: u32,
+    pub duplicate: u32,
+    pub failure: u32,
+    pub skipped: u32,
+    pub quarantined: u32,
+    pub near_duplicate: u32,
+    pub errors: Vec<ItemError>,
+    // The limit stopped the page before its last item
+    pub partial: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ItemError {
+    pub page: u16,
+    pub hash_id: String,
+    pub message: String,
+}
+
+// What the crawl loop does with each page of upstream items, storing them outside of tests
+#[async_trait]
+pub trait PageSink: Send {
+    async fn store_page(
+        &mut self,
+        page: u16,
+        items: &[Value],
+        limit: Option<u32>,
+    ) -> Result<PageOutcome>;
+}
+
+// Inserts the items, then records the page in crawl_progress and on the job
+struct StoreSink<'a> {
+    state: &'a AppState,
+    payload: &'a IngestPayload,
+    source: &'a dyn ImageSource,
+    duplicates: Option<DuplicatePolicy>,
+    job: &'a JobHandle,
+    tags_key: String,
+}
+
+#[async_trait]
+impl PageSink for StoreSink<'_> {
+    async fn store_page(
+        &mut self,
+        page: u16,
+        items: &[Value],
+        limit: Option<u32>,
+    ) -> Result<PageOutcome> {
+        let pool = &self.state.pool;
+        let payloads = normalize_items(self.source, items, self.payload);
+        let outcome = ingest_items(
+            self.state,
+            self.source.name(),
+            payloads,
+            page,
+            limit,
+            self.duplicates.as_ref(),
+        )
+        .await?;
+
+        save_progress(
+            pool,
+            self.source.name(),
+            &self.payload.q,
+            &self.tags_key,
+            self.payload.category.as_deref().unwrap_or_default(),
+            progress_page(page, &outcome),
+            outcome.inserted,
+        )
+        .await?;
+        self.job.record_page(pool, page, &outcome).await?;
+
+        Ok(outcome)
+    }
+}
+
+// The last page a resume can skip, a page the limit cut short is read again
+pub fn progress_page(page: u16, outcome: &PageOutcome) -> u16 {
+    match outcome.partial {
+        true => page.saturating_sub(1),
+        false => page,
+    }
+}
+
+pub async fn crawl(
+    state: &AppState,
+    payload: &IngestPayload,
+    job: &JobHandle,
+) -> Result<CrawlReport> {
+    let source = source_for(state, payload).map_err(|err| eyre!(err))?;
+    let search = source.search(payload)?;
+
+    // Near-duplicates can only be told apart from the image files
+    let duplicates = payload.download.then(|| DuplicatePolicy {
+        action: payload
+            .near_duplicates
+            .unwrap_or(state.duplicate_policy.action),
+        max_distance: payload
+            .near_duplicate_distance
+            .unwrap_or(state.duplicate_policy.max_distance),
+    });
+    let options = CrawlOptions::from(payload);
+    let mut sink = StoreSink {
+        state,
+        payload,
+        source: source.as_ref(),
+        duplicates,
+        job,
+        tags_key: payload.tags.join(","),
+    };
+
+    let mut first_page = options.start_page;
+    if options.resume {
+        let last_page = load_progress(
+            &state.pool,
+            source.name(),
+            &payload.q,
+            &sink.tags_key,
+            payload.category.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        first_page = resume_page(first_page, last_page);
+    }
+
+    crawl_pages(
+        source.as_ref(),
+        &search,
+        &options,
+        first_page,
+        job,
+        &mut sink,
+    )
+    .await
+}
+
+// A resumed crawl continues after the last page stored for the same search
+pub fn resume_page(start_page: u16, last_page: Option<u16>) -> u16 {
+    last_page.map_or(start_page, |page| page.saturating_add(1))
+}
+
+// Walks the pages from `first_page` until one of the stop reasons of CrawlReport
+pub async fn crawl_pages(
+    source: &dyn ImageSource,
+    search: &Value,
+    options: &CrawlOptions,
+    first_page: u16,
+    job: &JobHandle,
+    sink: &mut dyn PageSink,
+) -> Result<CrawlReport> {
+    let mut page = first_page;
+    let end_page = options
+        .end_page
+        .unwrap_or_else(|| page.saturating_add(DEFAULT_MAX_PAGES - 1));
+
+    let mut report = CrawlReport {
+        first_page: page,
+        ..Default::default()
+    };
+
+    loop {
+        if page > end_page {
+            report.stop_reason = "end_page";
+            break;
+        }
+
+        if report.pages_crawled > 0 && !options.delay.is_zero() {
+            tokio::time::sleep(options.delay).await;
+        }
+
+        if job.is_cancelled() {
+            report.stop_reason = "cancelled";
+            break;
+        }
+
+        let items = source.page(search, page).await?;
+
+        if items.is_empty() {
+            report.stop_reason = "no_items";
+            break;
+        }
+
+        let remaining = options
+            .max_items
+            .map(|max| max.saturating_sub(report.total_inserted));
+        let outcome = sink.store_page(page, &items, remaining).await?;
+
+        report.last_page = page;
+        report.pages_crawled += 1;
+        report.total_inserted += outcome.inserted;
+        report.total_duplicate += outcome.duplicate;
+        report.total_failure += outcome.failure;
+        report.total_skipped += outcome.skipped;
+        report.total_quarantined += outcome.quarantined;
+        report.total_near_duplicate += outcome.near_duplicate;
+
+        if options
+            .max_items
+            .is_some_and(|max| report.total_inserted >= max)
+        {
+            report.stop_reason = "max_items";
+            break;
+        }
+
+        if options.end_page.is_none()
+            && outcome.inserted == 0
+            && outcome.failure == 0
+            && outcome.skipped == 0
+        {
+            report.stop_reason = "only_duplicates";
+            break;
+        }
+
+        page = match page.checked_add(1) {
+            Some(next) => next,
+            None => {
+                report.stop_reason = "end_page";
+                break;
+            }
+        };
+    }
+
+    Ok(report)
+}
+
+// Rows for a page of upstream items, tagged with the tags they were searched by
+pub fn normalize_items(
+    source: &dyn ImageSource,
+    items: &[Value],
+    payload: &IngestPayload,
+) -> Vec<CreatePayload> {
+    items
+        .iter()
+        .map(|item| {
+            let mut row = source.normalize(item, payload.category.as_ref());
+            row.tags = normalize_tags(row.tags.iter().chain(&payload.tags).map(String::as_str));
+            row
+        })
+        .collect()
+}
+
+// With a duplicate policy every new image is downloaded and checked against the stored hashes
+pub async fn ingest_items(
+    state: &AppState,
+    source: &str,
+    payloads: Vec<CreatePayload>,
+    page: u16,
+    limit: Option<u32>,
+    duplicates: Option<&DuplicatePolicy>,
+) -> Result<PageOutcome> {
+    let pool = &state.pool;
+    let policy = &state.ingest_policy;
+    let mut seen = existing_hash_ids(pool, source, &payloads).await?;
+
+    let mut outcome = PageOutcome::default();
+
+    for mut payload in payloads {
+        if limit.is_some_and(|limit| outcome.inserted >= limit) {
+            outcome.partial = true;
+            break;
+        }
+
+        if let Err(message) = validate_item(&payload) {
+            outcome.failure += 1;
+            outcome.errors.push(ItemError {
+                page,
+                hash_id: payload.hash_id,
+                message,
+            });
+            continue;
+        }
+
+        if !seen.insert(payload.hash_id.clone()) {
+            outcome.duplicate += 1;
+            continue;
+        }
+
+        match policy.action_for(payload.content_rating) {
+            RatingAction::Allow => {}
+            RatingAction::Quarantine => payload.quarantined = true,
+            RatingAction::Skip => {
+                outcome.skipped += 1;
+                continue;
+            }
+        }
+
+        // Without the file the item can not be checked, it is still stored and the error kept
+        let downloaded = match duplicates {
+            Some(_) => match download_and_inspect(state, &payload.image).await {
+                Ok(downloaded) => Some(downloaded),
+                Err(err) => {
+                    outcome.errors.push(ItemError {
+                        page,
+                        hash_id: payload.hash_id.clone(),
+                        message: format!("download failed, stored without a hash: {err}"),
+                    });
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut near_duplicate_of = None;
+        if let (Some(duplicates), Some((_, facts))) = (duplicates, &downloaded) {
+            let closest = match duplicates.action {
+                DuplicateAction::Allow => None,
+                _ => state.similar.closest(facts.phash, duplicates.max_distance),
+            };
+
+            if let Some((id, _)) = closest {
+                outcome.near_duplicate += 1;
+
+                if duplicates.action == DuplicateAction::Skip {
+                    continue;
+                }
+                near_duplicate_of = Some(id);
+            }
+        }
+
+        let quarantined = payload.quarantined;
+        let hash_id = payload.hash_id.clone();
+
+        match Operation::Create(payload).execute(pool).await {
+            Ok(created) => {
+                outcome.inserted += 1;
+                outcome.quarantined += u32::from(quarantined);
+
+                if let (OperationResult::DataStruct(id, ..), Some((image, facts))) =
+                    (created, &downloaded)
+                {
+                    // The row is stored, a failed analysis is kept like a failed download
+                    if let Err(err) =
+                        store_analysis(pool, &state.similar, id, facts, near_duplicate_of).await
+                    {
+                        outcome.errors.push(ItemError {
+                            page,
+                            hash_id: hash_id.clone(),
+                            message: format!("analysis failed, stored without it: {err}"),
+                        });
+                    }
+
+                    // Missing variants are not worth failing the item over
+                    if let Err(err) = generate_variants(pool, &state.variants, id, image).await {
+                        eprintln!("No variants for image {id}: {err}");
+                    }
+                }
+            }
+            Err(err) => {
+                outcome.failure += 1;
+                outcome.errors.push(ItemError {
+                    page,
+                    hash_id,
+                    message: err.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(outcome)
+}
+
+async fn download_and_inspect(
+    state: &AppState,
+    image_url: &str,
+) -> Result<(DownloadedImage, ImageFacts)> {
+    let image = state.downloader.download(image_url).await?;
+    let facts = inspect_download(&image).await?;
+
+    Ok((image, facts))
+}
+
+// The hash_ids of a page that are already stored for the source
+pub async fn existing_hash_ids(
+    pool: &Pool<Postgres>,
+    source: &str,
+    payloads: &[CreatePayload],
+) -> Result<HashSet<String>, sqlx::Error> {
+    let hash_ids = payloads.iter().map(|p| p.hash_id.clone()).collect();
+    let existing = Operation::ExistingHashIds(source.to_string(), hash_ids)
+        .execute(pool)
+        .await?;
+
+    Ok(match existing {
+        OperationResult::HashIds(hash_ids) => hash_ids.into_iter().collect(),
+        _ => HashSet::new(),
+    })
+}
+
+// Items an upstream returns without an id or a usable image URL are never stored
+pub fn validate_item(payload: &CreatePayload) -> Result<(), String> {
+    if payload.hash_id.trim().is_empty() {
+        return Err("missing hash_id".to_string());
+    }
+
+    match Url::parse(&payload.image) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(format!("invalid image url `{}`", payload.image)),
+    }
+}
+
+async fn load_progress(
+    pool: &Pool<Postgres>,
+    source: &str,
+    keyword: &str,
+    tags: &str,
+    category: &str,
+) -> Result<Option<u16>, sqlx::Error> {
+    let last_page = sqlx::query_scalar!(
+        r#"
+        SELECT last_page
+        FROM crawl_progress
+        WHERE source = $1 AND keyword = $2 AND tags = $3 AND category = $4
+        "#,
+        source,
+        keyword,
+        tags,
+        category,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(last_page.map(|page| page.clamp(0, u16::MAX as i32) as u16))
+}
+
+async fn save_progress(
+    pool: &Pool<Postgres>,
+    source: &str,
+    keyword: &str,
+    tags: &str,
+    category: &str,
+    page: u16,
+    inserted: u32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO crawl_progress (source, keyword, tags, category, last_page, total_inserted)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (source, keyword, tags, category) DO UPDATE
+        SET
+            last_page = EXCLUDED.last_page,
+            total_inserted = crawl_progress.total_inserted + EXCLUDED.total_inserted,
+            updated_date = NOW()
+        "#,
+        source,
+        keyword,
+        tags,
+        category,
+        page as i32,
+        inserted as i32,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{routing::post, Json, Router};
+    use serde_json::json;
+
+    use super::*;
+    use crate::ipfs_router::{jobs::JobRegistry, seaart_resp::SeaArtClient};
+
+    // Counts every id it has not been handed before as inserted, like the hash_id check
+    #[derive(Default)]
+    struct MemorySink {
+        seen: HashSet<String>,
+        pages: Vec<u16>,
+    }
+
+    #[async_trait]
+    impl PageSink for MemorySink {
+        async fn store_page(
+            &mut self,
+            page: u16,
+            items: &[Value],
+            limit: Option<u32>,
+        ) -> Result<PageOutcome> {
+            self.pages.push(page);
+            let mut outcome = PageOutcome::default();
+
+            for item in items {
+                if limit.is_some_and(|limit| outcome.inserted >= limit) {
+                    outcome.partial = true;
+                    break;
+                }
+
+                match self.seen.insert(item["id"].as_str().unwrap().to_string()) {
+                    true => outcome.inserted += 1,
+                    false => outcome.duplicate += 1,
+                }
+            }
+
+            Ok(outcome)
+        }
+    }
+
+    // A SeaArt list endpoint serving `pages`, page 1 first, and no items after the last
+    fn mock_source(pages: Vec<Vec<&'static str>>) -> SeaArtClient {
+        let pages = Arc::new(pages);
+        let app = Router::new().route(
+            "/api/v1/artwork/list",
+            post(|Json(body): Json<Value>| async move {
+                let page = body["page"].as_u64().unwrap() as usize;
+                let ids = pages.get(page - 1).cloned().unwrap_or_default();
+                let items: Vec<Value> = ids.into_iter().map(|id| json!({ "id": id })).collect();
+
+                Json(json!({ "data": { "items": items } }))
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        SeaArtClient::new(format!("http://{addr}/")).unwrap()
+    }
+
+    fn options(end_page: Option<u16>, max_items: Option<u32>) -> CrawlOptions {
+        CrawlOptions {
+            start_page: 1,
+            end_page,
+            max_items,
+            delay: Duration::ZERO,
+            resume: true,
+        }
+    }
+
+    async fn run(
+        pages: Vec<Vec<&'static str>>,
+        options: &CrawlOptions,
+        first_page: u16,
+        job: &JobHandle,
+    ) -> (CrawlReport, MemorySink) {
+        let source = mock_source(pages);
+        let search = source.search_body("", vec![], None);
+        let mut sink = MemorySink::default();
+
+        let report = crawl_pages(&source, &search, options, first_page, job, &mut sink)
+            .await
+            .unwrap();
+
+        (report, sink)
+    }
+
+    #[tokio::test]
+    async fn crawl_stop_test() {
+        let jobs = JobRegistry::default();
+        let job = jobs.register(1);
+
+        // Runs until a page comes back empty
+        let pages = vec![vec!["a", "b"], vec!["c"]];
+        let (report, sink) = run(pages, &options(None, None), 1, &job).await;
+        assert_eq!("no_items", report.stop_reason);
+        assert_eq!(vec![1, 2], sink.pages);
+        assert_eq!((2, 3), (report.pages_crawled, report.total_inserted));
+
+        // A page of nothing but stored items ends an open-ended crawl
+        let pages = vec![vec!["a", "b"], vec!["b", "a"], vec!["c"]];
+        let (report, sink) = run(pages, &options(None, None), 1, &job).await;
+        assert_eq!("only_duplicates", report.stop_reason);
+        assert_eq!(vec![1, 2], sink.pages);
+        assert_eq!((2, 2), (report.total_inserted, report.total_duplicate));
+
+        // With an explicit end_page the duplicates are walked past
+        let pages = vec![vec!["a"], vec!["a"], vec!["b"], vec!["c"]];
+        let (report, sink) = run(pages, &options(Some(3), None), 1, &job).await;
+        assert_eq!("end_page", report.stop_reason);
+        assert_eq!(vec![1, 2, 3], sink.pages);
+        assert_eq!(3, report.last_page);
+
+        // The cap applies inside a page as well
+        let pages = vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]];
+        let (report, sink) = run(pages, &options(None, Some(3)), 1, &job).await;
+        assert_eq!("max_items", report.stop_reason);
+        assert_eq!(vec![1, 2], sink.pages);
+        assert_eq!(3, report.total_inserted);
+        assert!(!sink.seen.contains("d"));
+
+        jobs.cancel(1);
+        let (report, sink) = run(vec![vec!["a"]], &options(None, None), 1, &job).await;
+        assert_eq!("cancelled", report.stop_reason);
+        assert!(sink.pages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn crawl_resume_test() {
+        assert_eq!(1, resume_page(1, None));
+        assert_eq!(3, resume_page(1, Some(2)));
+        assert_eq!(u16::MAX, resume_page(1, Some(u16::MAX)));
+
+        // A page max_items cut short is crawled again on resume
+        let mut outcome = PageOutcome::default();
+        assert_eq!(4, progress_page(4, &outcome));
+        outcome.partial = true;
+        assert_eq!(3, progress_page(4, &outcome));
+        assert_eq!(4, resume_page(1, Some(progress_page(4, &outcome))));
+
+        let job = JobRegistry::default().register(1);
+        let pages = vec![vec!["a"], vec!["b"], vec!["c"], vec!["d"]];
+        let first_page = resume_page(1, Some(2));
+
+        let (report, sink) = run(pages, &options(None, None), first_page, &job).await;
+        assert_eq!("no_items", report.stop_reason);
+        assert_eq!(3, report.first_page);
+        assert_eq!(vec![3, 4], sink.pages);
+        assert_eq!(vec!["c", "d"], {
+            let mut seen: Vec<_> = sink.seen.into_iter().collect();
+            seen.sort();
+            seen
+        });
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use eyre::{eyre, Result};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, ClientBuilder, Response, Url,
+};
+
+const MAX_REDIRECTS: usize = 10;
+
+// Scraped pages and image URLs come from third parties, so by default they may only reach
+// public http(s) hosts. FETCH_ALLOW_PRIVATE=true lifts the address check for local development.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FetchGuard {
+    pub allow_private: bool,
+}
+
+impl FetchGuard {
+    pub fn from_env() -> Self {
+        FetchGuard {
+            allow_private: env::var("FETCH_ALLOW_PRIVATE").is_ok_and(|val| val == "true"),
+        }
+    }
+
+    // Host names are checked once resolved, by the resolver `apply` installs
+    pub fn check(&self, url: &Url) -> Result<()> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(eyre!(
+                "refusing to fetch `{url}`: only http and https are allowed"
+            ));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| eyre!("refusing to fetch `{url}`: no host"))?;
+        let Ok(ip) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        else {
+            return Ok(());
+        };
+
+        if !self.allow_private && !is_public_ip(ip) {
+            return Err(eyre!(
+                "refusing to fetch `{url}`: {ip} is not a public address"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Every redirect hop is checked again, and names only resolve to public addresses
+    pub fn apply(self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = builder.redirect(redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+
+            match self.check(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(err) => attempt.error(err.to_string()),
+            }
+        }));
+
+        match self.allow_private {
+            true => builder,
+            false => builder.dns_resolver(Arc::new(PublicResolver)),
+        }
+    }
+}
+
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// The body of a third party response, refused once it passes `max_bytes`. Content-Length can
+// be missing or wrong, so the body is counted as it arrives.
+pub async fn read_capped(mut resp: Response, max_bytes: u64) -> Result<Vec<u8>> {
+    let too_large = |url: &Url| eyre!("{url} is larger than {max_bytes} bytes");
+
+    if resp.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large(resp.url()));
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if (bytes.len() + chunk.len()) as u64 > max_bytes {
+            return Err(too_large(resp.url()));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240
+        // Carrier-grade NAT, IETF protocol assignments and benchmarking
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link-local and documentation ranges
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn public_ip_test() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn check_url_test() {
+        let guard = FetchGuard::default();
+        let check = |url: &str| guard.check(&Url::parse(url).unwrap());
+
+        assert!(check("https://example.com/a.png").is_ok());
+        assert!(check("http://93.184.216.34/a.png").is_ok());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check("ftp://example.com/a.png").is_err());
+        assert!(check("http://127.0.0.1:8080/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data").is_err());
+
+        let local = FetchGuard {
+            allow_private: true,
+        };
+        assert!(local
+            .check(&Url::parse("http://127.0.0.1/").unwrap())
+            .is_ok());
+        assert!(local
+            .check(&Url::parse("file:///etc/passwd").unwrap())
+            .is_err());
+    }
+}
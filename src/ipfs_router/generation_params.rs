@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::prompt::split_negative;
+
+// What Stable Diffusion front ends write into the `parameters` text chunk of their PNGs
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub prompt: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub steps: Option<i64>,
+    pub sampler: Option<String>,
+    pub cfg_scale: Option<f64>,
+    pub seed: Option<i64>,
+    pub size: Option<String>,
+    pub model_hash: Option<String>,
+    pub model: Option<String>,
+    // Every `Key: value` of the settings line as written, including the ones above
+    pub settings: BTreeMap<String, String>,
+}
+
+impl GenerationParams {
+    // The prompts in the A1111 layout `parse_prompt` understands, without the settings line
+    pub fn prompt_text(&self) -> Option<String> {
+        let prompt = self.prompt.as_deref().unwrap_or_default();
+
+        match (prompt, self.negative_prompt.as_deref()) {
+            ("", None) => None,
+            (prompt, None) => Some(prompt.to_string()),
+            (prompt, Some(negative)) => Some(format!("{prompt}\nNegative prompt: {negative}")),
+        }
+    }
+}
+
+// The `parameters` tEXt, zTXt or iTXt chunk of a PNG, None for anything else
+pub fn png_parameters(bytes: &[u8]) -> Option<String> {
+    let mut reader = png::Decoder::new(bytes).read_info().ok()?;
+    // Chunks written after the image data are only read by `finish`
+    if let Err(err) = reader.finish() {
+        eprintln!("PNG text chunks after the image data were not read: {err}");
+    }
+
+    let info = reader.info();
+    let latin1 = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), Ok(chunk.text.clone())));
+    let compressed = info
+        .compressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.get_text()));
+    let utf8 = info
+        .utf8_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.get_text()));
+
+    latin1
+        .chain(compressed)
+        .chain(utf8)
+        .find(|(keyword, _)| keyword.eq_ignore_ascii_case("parameters"))
+        .and_then(|(_, text)| text.ok())
+        .filter(|text| !text.trim().is_empty())
+}
+
+pub fn parse_parameters(text: &str) -> GenerationParams {
+    let text = text.trim();
+
+    // The settings are the last line, starting with `Steps:`
+    let (body, settings) = match text.rfind("\nSteps:") {
+        Some(start) => (&text[..start], &text[start + 1..]),
+        None if text.starts_with("Steps:") => ("", text),
+        None => (text, ""),
+    };
+
+    let (prompt, negative) = split_negative(body);
+    let settings = parse_settings(settings);
+
+    let setting = |key: &str| settings.get(key).cloned();
+    let number = |key: &str| settings.get(key).and_then(|val| val.parse().ok());
+
+    GenerationParams {
+        prompt: Some(prompt.trim().to_string()).filter(|prompt| !prompt.is_empty()),
+        negative_prompt: negative
+            .map(|negative| negative.trim().to_string())
+            .filter(|negative| !negative.is_empty()),
+        steps: number("Steps"),
+        sampler: setting("Sampler"),
+        cfg_scale: settings.get("CFG scale").and_then(|val| val.parse().ok()),
+        seed: number("Seed"),
+        size: setting("Size"),
+        model_hash: setting("Model hash"),
+        model: setting("Model"),
+        settings,
+    }
+}
+
+// `Key: value, Key: "quoted, value"` pairs, quotes keep commas inside a value
+fn parse_settings(line: &str) -> BTreeMap<String, String> {
+    let mut settings = BTreeMap::new();
+    let mut rest = line.trim();
+
+    while let Some(colon) = rest.find(':') {
+        let key = rest[..colon].trim().trim_start_matches(',').trim();
+        let after = rest[colon + 1..].trim_start();
+
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = quoted.len();
+                let mut chars = quoted.char_indices();
+
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+
+        if !key.is_empty() {
+            settings.insert(key.to_string(), value);
+        }
+        rest = next.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+
+    settings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_test() {
+        let params = parse_parameters(
+            "masterpiece, (red hair:1.2), <lora:foo:0.7>\n\
+             Negative prompt: lowres, bad hands\n\
+             Steps: 28, Sampler: DPM++ 2M Karras, CFG scale: 7.5, Seed: 3920144251, \
+             Size: 512x768, Model hash: 7f96a1a9ca, Model: anything-v5, \
+             Lora hashes: \"foo: 1a2b3c, bar: 4d5e6f\", Version: v1.6.0",
+        );
+
+        assert_eq!(
+            Some("masterpiece, (red hair:1.2), <lora:foo:0.7>"),
+            params.prompt.as_deref()
+        );
+        assert_eq!(Some("lowres, bad hands"), params.negative_prompt.as_deref());
+        assert_eq!(Some(28), params.steps);
+        assert_eq!(Some("DPM++ 2M Karras"), params.sampler.as_deref());
+        assert_eq!(Some(7.5), params.cfg_scale);
+        assert_eq!(Some(3920144251), params.seed);
+        assert_eq!(Some("7f96a1a9ca"), params.model_hash.as_deref());
+        assert_eq!(Some("anything-v5"), params.model.as_deref());
+        assert_eq!(
+            Some("foo: 1a2b3c, bar: 4d5e6f"),
+            params.settings.get("Lora hashes").map(String::as_str)
+        );
+        assert_eq!(
+            Some("v1.6.0"),
+            params.settings.get("Version").map(String::as_str)
+        );
+        assert_eq!(
+            Some(
+                "masterpiece, (red hair:1.2), <lora:foo:0.7>\nNegative prompt: lowres, bad hands"
+                    .to_string()
+            ),
+            params.prompt_text()
+        );
+
+        let settings_only = parse_parameters("Steps: 20, Sampler: Euler a");
+        assert_eq!(None, settings_only.prompt);
+        assert_eq!(None, settings_only.prompt_text());
+        assert_eq!(Some(20), settings_only.steps);
+    }
+
+    #[test]
+    fn png_parameters_test() {
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(Cursor::new(&mut png), 1, 1);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder
+                .add_itxt_chunk(
+                    "parameters".to_string(),
+                    "1girl, 猫耳\nSteps: 20, Seed: 1".to_string(),
+                )
+                .unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0]).unwrap();
+        }
+
+        let text = png_parameters(&png).unwrap();
+        assert_eq!(
+            Some("1girl, 猫耳"),
+            parse_parameters(&text).prompt.as_deref()
+        );
+        assert_eq!(None, png_parameters(b"GIF89a"));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{env, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use eyre::{eyre, Result};
+use reqwest::{Client, Url};
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    content_rating::ContentRating,
+    fetch_guard::{read_capped, FetchGuard},
+    image_source::ImageSource,
+    seaart_resp::USER_AGENT,
+    upstream::Upstreams,
+    CreatePayload, IngestPayload,
+};
+
+// CSS selectors used to pull images out of an arbitrary page, `item` defaults to every `img`
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HtmlSelectors {
+    item: Option<String>,
+    image: Option<String>,
+    caption: Option<String>,
+    image_attr: Option<String>,
+}
+
+// Larger pages are refused instead of being buffered
+const DEFAULT_MAX_BYTES: u64 = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct HtmlSource {
+    client: Client,
+    guard: FetchGuard,
+    upstreams: Arc<Upstreams>,
+    max_bytes: u64,
+}
+
+impl HtmlSource {
+    pub fn new(guard: FetchGuard) -> Result<Self, reqwest::Error> {
+        let client = guard
+            .apply(Client::builder())
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(HtmlSource {
+            client,
+            guard,
+            upstreams: Arc::new(Upstreams::default()),
+            max_bytes: DEFAULT_MAX_BYTES,
+        })
+    }
+
+    // HTML_MAX_BYTES overrides the size limit of a scraped page
+    pub fn from_env(guard: FetchGuard) -> Result<Self, reqwest::Error> {
+        let mut source = HtmlSource::new(guard)?;
+
+        if let Some(max_bytes) = env::var("HTML_MAX_BYTES")
+            .ok()
+            .and_then(|val| val.parse().ok())
+        {
+            source = source.with_max_bytes(max_bytes);
+        }
+
+        Ok(source)
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_upstreams(mut self, upstreams: Arc<Upstreams>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+}
+
+fn parse_selector(selector: &str) -> Result<Selector> {
+    Selector::parse(selector).map_err(|err| eyre!("invalid selector `{selector}`: {err:?}"))
+}
+
+fn squash_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn extract_images(html: &str, page_url: &Url, selectors: &HtmlSelectors) -> Result<Vec<Value>> {
+    let item_selector = parse_selector(selectors.item.as_deref().unwrap_or("img"))?;
+    let image_selector = selectors.image.as_deref().map(parse_selector).transpose()?;
+    let caption_selector = selectors
+        .caption
+        .as_deref()
+        .map(parse_selector)
+        .transpose()?;
+    let image_attr = selectors.image_attr.as_deref().unwrap_or("src");
+    let img = parse_selector("img")?;
+
+    let document = Html::parse_document(html);
+
+    // A <base href> changes what relative URLs on the page resolve against
+    let base_url = document
+        .select(&parse_selector("base[href]")?)
+        .next()
+        .and_then(|base| base.value().attr("href"))
+        .and_then(|href| page_url.join(href).ok())
+        .unwrap_or_else(|| page_url.clone());
+
+    let mut items = Vec::new();
+
+    for item in document.select(&item_selector) {
+        let image: Option<ElementRef> = match &image_selector {
+            Some(selector) => item.select(selector).next(),
+            None if item.value().name() == "img" => Some(item),
+            None => item.select(&img).next(),
+        };
+        let Some(image) = image else {
+            continue;
+        };
+
+        let src = image
+            .value()
+            .attr(image_attr)
+            .or_else(|| image.value().attr("data-src"))
+            .map(str::trim)
+            .filter(|src| !src.is_empty() && !src.starts_with("data:"));
+        let Some(url) = src.and_then(|src| base_url.join(src).ok()) else {
+            continue;
+        };
+
+        let caption = match &caption_selector {
+            Some(selector) => item
+                .select(selector)
+                .next()
+                .map(|caption| caption.text().collect::<String>()),
+            None => image
+                .value()
+                .attr("alt")
+                .or_else(|| image.value().attr("title"))
+                .map(str::to_string),
+        }
+        .map(|caption| squash_whitespace(&caption))
+        .filter(|caption| !caption.is_empty());
+
+        let dimension = |name: &str| {
+            image
+                .value()
+                .attr(name)
+                .and_then(|val| val.trim().trim_end_matches("px").parse::<i32>().ok())
+                .unwrap_or_default()
+        };
+
+        items.push(json!({
+            "url": url.as_str(),
+            "caption": caption,
+            "width": dimension("width"),
+            "height": dimension("height"),
+            "page_url": page_url.as_str()
+        }));
+    }
+
+    Ok(items)
+}
+
+#[async_trait]
+impl ImageSource for HtmlSource {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
+    fn search(&self, query: &IngestPayload) -> Result<Value> {
+        let url = query
+            .url
+            .as_deref()
+            .ok_or_else(|| eyre!("html source needs a `url`"))?;
+        let url = Url::parse(url)?;
+        self.guard.check(&url)?;
+        let selectors = query.selectors.clone().unwrap_or_default();
+
+        // Fail before the job starts when a selector does not parse
+        extract_images("", &url, &selectors)?;
+
+        Ok(json!({ "url": url.as_str(), "selectors": selectors }))
+    }
+
+    async fn page(&self, search: &Value, page: u16) -> Result<Vec<Value>> {
+        // A scraped page is a single page of results
+        if page > 1 {
+            return Ok(Vec::new());
+        }
+
+        let url = search["url"]
+            .as_str()
+            .ok_or_else(|| eyre!("html search has no url"))?;
+        let url = Url::parse(url)?;
+        self.guard.check(&url)?;
+        let selectors: HtmlSelectors = serde_json::from_value(search["selectors"].clone())?;
+
+        // Every scraped site gets a breaker of its own
+        let host = url.host_str().unwrap_or("html").to_string();
+        let resp = self
+            .upstreams
+            .send(&host, self.client.get(url))
+            .await?
+            .error_for_status()?;
+        let page_url = resp.url().clone();
+        let body = read_capped(resp, self.max_bytes).await?;
+
+        extract_images(&String::from_utf8_lossy(&body), &page_url, &selectors)
+    }
+
+    fn normalize(&self, item: &Value, category: Option<&String>) -> CreatePayload {
+        let url = item["url"].as_str().unwrap_or_default();
+
+        CreatePayload {
+            image: url.to_string(),
+            ipfs_image_url: "NO_IPFS".to_string(),
+            category: category.cloned(),
+            width: item["width"].as_i64().unwrap_or_default() as i32,
+            height: item["height"].as_i64().unwrap_or_default() as i32,
+            prompt: item["caption"].as_str().map(str::to_string),
+            hash_id: url.to_string(),
+            author: None,
+            model_id: None,
+            channel: None,
+            num_of_like: 0,
+            num_of_collection: 0,
+            num_of_view: 0,
+            // Nothing on an arbitrary page says what it shows, the ingest policy decides
+            content_rating: ContentRating::Unrated,
+            quarantined: false,
+            source: Some(self.name().to_string()),
+            tags: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    const PAGE: &str = r#"
+        <html>
+          <body>
+            <img src="/static/logo.png" alt="Logo">
+            <figure class="art">
+              <img data-src="renders/mecha.png" width="1536" height="2048px" alt="ignored">
+              <figcaption>  Gundam   in the rain </figcaption>
+            </figure>
+            <figure class="art">
+              <img src="data:image/png;base64,AAAA">
+            </figure>
+          </body>
+        </html>
+    "#;
+
+    #[test]
+    fn extract_default_test() {
+        let url = Url::parse("https://example.com/gallery/index.html").unwrap();
+        let items = extract_images(PAGE, &url, &HtmlSelectors::default()).unwrap();
+
+        assert_eq!(2, items.len());
+        assert_eq!("https://example.com/static/logo.png", items[0]["url"]);
+        assert_eq!("Logo", items[0]["caption"]);
+    }
+
+    #[test]
+    fn extract_selector_test() {
+        let url = Url::parse("https://example.com/gallery/index.html").unwrap();
+        let selectors = HtmlSelectors {
+            item: Some("figure.art".to_string()),
+            caption: Some("figcaption".to_string()),
+            ..Default::default()
+        };
+        let items = extract_images(PAGE, &url, &selectors).unwrap();
+
+        assert_eq!(1, items.len());
+        assert_eq!(
+            "https://example.com/gallery/renders/mecha.png",
+            items[0]["url"]
+        );
+        assert_eq!("Gundam in the rain", items[0]["caption"]);
+        assert_eq!(1536, items[0]["width"]);
+        assert_eq!(2048, items[0]["height"]);
+
+        let invalid = HtmlSelectors {
+            item: Some("figure[".to_string()),
+            ..Default::default()
+        };
+        assert!(extract_images(PAGE, &url, &invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn max_bytes_test() {
+        let app = Router::new().route("/", get(|| async { PAGE }));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let guard = FetchGuard {
+            allow_private: true,
+        };
+        let search = json!({ "url": format!("http://{addr}/"), "selectors": {} });
+
+        let source = HtmlSource::new(guard).unwrap();
+        assert!(!source.page(&search, 1).await.unwrap().is_empty());
+
+        let capped = source.with_max_bytes(64).page(&search, 1).await;
+        assert!(capped
+            .unwrap_err()
+            .to_string()
+            .contains("larger than 64 bytes"));
+    }
+}
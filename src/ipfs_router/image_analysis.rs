@@ -0,0 +1,346 @@
+use std::{io::Cursor, time::Duration};
+
+use eyre::Result;
+use image::{
+    io::{Limits, Reader},
+    DynamicImage, GenericImageView,
+};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use serde_json::json;
+
+use super::{
+    generation_params::{parse_parameters, png_parameters, GenerationParams},
+    image_download::{DownloadedImage, ImageDownloader},
+    near_duplicate::dhash,
+    palette::{extract_palette, PaletteColor},
+    placeholder::{placeholder, Placeholder},
+    prompt::parse_prompt,
+    similar::SimilarIndex,
+};
+
+// Downloads are third party files, a small file can claim a huge canvas
+const DECODE_MAX_SIDE: u32 = 16_384;
+const DECODE_MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+// What decoding a downloaded file tells about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageFacts {
+    pub width: u32,
+    pub height: u32,
+    pub phash: u64,
+    pub generation: Option<GenerationParams>,
+    pub palette: Vec<PaletteColor>,
+    // A missing placeholder is not worth losing the rest over
+    pub placeholder: Option<Placeholder>,
+}
+
+// Decodes a downloaded file within DECODE_MAX_SIDE and DECODE_MAX_ALLOC
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(DECODE_MAX_SIDE);
+    limits.max_image_height = Some(DECODE_MAX_SIDE);
+    limits.max_alloc = Some(DECODE_MAX_ALLOC);
+
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+
+    Ok(reader.decode()?)
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DimensionCheck {
+    pub width: i32,
+    pub height: i32,
+    pub metadata_width: i32,
+    pub metadata_height: i32,
+    pub mismatch: bool,
+}
+
+pub fn inspect_image(bytes: &[u8]) -> Result<ImageFacts> {
+    let img = decode_image(bytes)?;
+    let (width, height) = img.dimensions();
+    let placeholder = placeholder(&img)
+        .map_err(|err| eprintln!("No placeholder for a {width}x{height} image: {err}"))
+        .ok();
+
+    Ok(ImageFacts {
+        width,
+        height,
+        phash: dhash(&img),
+        generation: png_parameters(bytes).map(|text| parse_parameters(&text)),
+        palette: extract_palette(&img),
+        placeholder,
+    })
+}
+
+// Decoding is CPU bound, keep it off the async workers
+pub async fn inspect_download(image: &DownloadedImage) -> Result<ImageFacts> {
+    let bytes = image.bytes.clone();
+
+    tokio::task::spawn_blocking(move || inspect_image(&bytes)).await?
+}
+
+// Runs whenever the file of a row is downloaded, whatever the download was for
+pub async fn analyze_download(
+    pool: &Pool<Postgres>,
+    index: &SimilarIndex,
+    id: i32,
+    image: &DownloadedImage,
+) -> Result<DimensionCheck> {
+    let facts = inspect_download(image).await?;
+    store_analysis(pool, index, id, &facts, None)
+        .await
+        .map_err(Into::into)
+}
+
+pub async fn store_analysis(
+    pool: &Pool<Postgres>,
+    index: &SimilarIndex,
+    id: i32,
+    facts: &ImageFacts,
+    near_duplicate_of: Option<i32>,
+) -> Result<DimensionCheck, sqlx::Error> {
+    // Embedded prompts only fill in for a missing upstream one
+    let embedded_prompt = facts
+        .generation
+        .as_ref()
+        .and_then(GenerationParams::prompt_text);
+
+    sqlx::query!(
+        r#"
+        UPDATE ipfs_image
+        SET
+            phash = $2,
+            near_duplicate_of = COALESCE($3, near_duplicate_of),
+            generation_params = COALESCE($4, generation_params),
+            blurhash = COALESCE($7, blurhash),
+            average_color = COALESCE($8, average_color),
+            prompt = CASE
+                WHEN NULLIF(prompt, '') IS NULL THEN COALESCE($5, prompt)
+                ELSE prompt
+            END,
+            parsed_prompt = CASE
+                WHEN NULLIF(prompt, '') IS NULL AND $5::TEXT IS NOT NULL THEN $6
+                ELSE parsed_prompt
+            END
+        WHERE id = $1
+        "#,
+        id,
+        facts.phash as i64,
+        near_duplicate_of,
+        facts
+            .generation
+            .as_ref()
+            .map(|generation| json!(generation)),
+        embedded_prompt.as_deref(),
+        embedded_prompt
+            .as_deref()
+            .map(|prompt| json!(parse_prompt(prompt))),
+        facts
+            .placeholder
+            .as_ref()
+            .map(|placeholder| placeholder.blurhash.as_str()),
+        facts
+            .placeholder
+            .as_ref()
+            .map(|placeholder| placeholder.average_color.as_str()),
+    )
+    .execute(pool)
+    .await?;
+    index.insert(id, facts.phash);
+
+    store_palette(pool, id, &facts.palette).await?;
+
+    verify_dimensions(pool, id, facts.width as i32, facts.height as i32).await
+}
+
+// Rows stored before placeholders existed are analyzed again from their source file, a few at a
+// time and spaced out so the backfill does not compete with the server
+const BACKFILL_BATCH: i64 = 50;
+const BACKFILL_DELAY: Duration = Duration::from_millis(500);
+// Files that failed this many times are dead links, not a passing outage
+const BACKFILL_MAX_ATTEMPTS: i32 = 3;
+
+pub async fn backfill_placeholders(
+    pool: &Pool<Postgres>,
+    index: &SimilarIndex,
+    downloader: &ImageDownloader,
+) -> Result<u64> {
+    let mut filled = 0;
+    let mut after = 0;
+
+    loop {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, image
+            FROM ipfs_image
+            WHERE blurhash IS NULL AND image <> '' AND placeholder_attempts < $1 AND id > $2
+            ORDER BY id
+            LIMIT $3
+            "#,
+            BACKFILL_MAX_ATTEMPTS,
+            after,
+            BACKFILL_BATCH,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let Some(last) = rows.last() else {
+            return Ok(filled);
+        };
+        after = last.id;
+
+        for row in rows {
+            tokio::time::sleep(BACKFILL_DELAY).await;
+
+            let facts = match downloader.download(&row.image).await {
+                Ok(image) => inspect_download(&image).await,
+                Err(err) => Err(err),
+            };
+
+            let result = match facts {
+                Ok(facts) if facts.placeholder.is_some() => {
+                    store_analysis(pool, index, row.id, &facts, None)
+                        .await
+                        .map_err(Into::into)
+                }
+                Ok(_) => Err(eyre::eyre!("the file has no placeholder")),
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(_) => filled += 1,
+                Err(err) => {
+                    eprintln!("No placeholder for image {}: {err}", row.id);
+                    record_failed_placeholder(pool, row.id).await?;
+                }
+            }
+        }
+    }
+}
+
+async fn record_failed_placeholder(pool: &Pool<Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE ipfs_image
+        SET placeholder_attempts = placeholder_attempts + 1, placeholder_failed_at = NOW()
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn store_palette(
+    pool: &Pool<Postgres>,
+    id: i32,
+    palette: &[PaletteColor],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM image_colors WHERE image_id = $1", id)
+        .execute(&mut tx)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO image_colors (image_id, position, hex, l, a, b, share)
+        SELECT $1, position - 1, hex, l, a, b, share
+        FROM UNNEST($2::TEXT[], $3::FLOAT8[], $4::FLOAT8[], $5::FLOAT8[], $6::FLOAT8[])
+            WITH ORDINALITY AS colors (hex, l, a, b, share, position)
+        "#,
+        id,
+        &palette.iter().map(|c| c.hex.clone()).collect::<Vec<_>>(),
+        &palette.iter().map(|c| c.l).collect::<Vec<_>>(),
+        &palette.iter().map(|c| c.a).collect::<Vec<_>>(),
+        &palette.iter().map(|c| c.b).collect::<Vec<_>>(),
+        &palette.iter().map(|c| c.share).collect::<Vec<_>>(),
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await
+}
+
+// Corrects the stored dimensions, metadata that was present but wrong flags the row
+async fn verify_dimensions(
+    pool: &Pool<Postgres>,
+    id: i32,
+    width: i32,
+    height: i32,
+) -> Result<DimensionCheck, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        WITH old AS (
+            SELECT id, width, height FROM ipfs_image WHERE id = $1 FOR UPDATE
+        )
+        UPDATE ipfs_image
+        SET
+            width = $2,
+            height = $3,
+            metadata_width = CASE
+                WHEN old.width <> $2 OR old.height <> $3 THEN old.width
+                ELSE ipfs_image.metadata_width
+            END,
+            metadata_height = CASE
+                WHEN old.width <> $2 OR old.height <> $3 THEN old.height
+                ELSE ipfs_image.metadata_height
+            END,
+            dimensions_mismatch = ipfs_image.dimensions_mismatch OR (
+                (old.width <> 0 OR old.height <> 0) AND (old.width <> $2 OR old.height <> $3)
+            ),
+            dimensions_verified_at = NOW(),
+            updated_date = CASE
+                WHEN old.width <> $2 OR old.height <> $3 THEN NOW()
+                ELSE ipfs_image.updated_date
+            END
+        FROM old
+        WHERE ipfs_image.id = old.id
+        RETURNING old.width AS metadata_width, old.height AS metadata_height, dimensions_mismatch
+        "#,
+        id,
+        width,
+        height,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(DimensionCheck {
+        width,
+        height,
+        metadata_width: row.metadata_width,
+        metadata_height: row.metadata_height,
+        mismatch: row.dimensions_mismatch,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inspect_test() {
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let facts = inspect_image(&png).unwrap();
+        assert_eq!((3, 2), (facts.width, facts.height));
+        assert!(inspect_image(b"<html></html>").is_err());
+        assert!(facts.placeholder.is_some());
+
+        // A tiny file with a canvas past the limits is refused before it is allocated
+        let mut wide = Vec::new();
+        image::GrayImage::new(DECODE_MAX_SIDE + 1, 1)
+            .write_to(&mut Cursor::new(&mut wide), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(wide.len() < 1024);
+        assert!(inspect_image(&wide).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{env, sync::Arc, time::Duration};
+
+use axum::body::Bytes;
+use eyre::Result;
+use reqwest::{header::CONTENT_TYPE, Client, Url};
+
+use super::{
+    fetch_guard::{read_capped, FetchGuard},
+    seaart_resp::USER_AGENT,
+    upstream::Upstreams,
+};
+
+#[derive(Debug)]
+pub struct DownloadedImage {
+    pub bytes: Bytes,
+    pub content_type: String,
+}
+
+// Larger originals are refused instead of being buffered
+const DEFAULT_MAX_BYTES: u64 = 32 * 1024 * 1024;
+
+// Fetches image files, each image host gets a circuit breaker of its own
+#[derive(Debug, Clone)]
+pub struct ImageDownloader {
+    client: Client,
+    guard: FetchGuard,
+    upstreams: Arc<Upstreams>,
+    max_bytes: u64,
+}
+
+impl ImageDownloader {
+    pub fn new(upstreams: Arc<Upstreams>, guard: FetchGuard) -> Result<Self, reqwest::Error> {
+        let client = guard
+            .apply(Client::builder())
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(60))
+            .build()?;
+
+        Ok(ImageDownloader {
+            client,
+            guard,
+            upstreams,
+            max_bytes: DEFAULT_MAX_BYTES,
+        })
+    }
+
+    // IMAGE_MAX_BYTES overrides the size limit of a single download
+    pub fn from_env(upstreams: Arc<Upstreams>, guard: FetchGuard) -> Result<Self, reqwest::Error> {
+        let mut downloader = ImageDownloader::new(upstreams, guard)?;
+
+        if let Some(max_bytes) = env::var("IMAGE_MAX_BYTES")
+            .ok()
+            .and_then(|val| val.parse().ok())
+        {
+            downloader = downloader.with_max_bytes(max_bytes);
+        }
+
+        Ok(downloader)
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub async fn download(&self, image_url: &str) -> Result<DownloadedImage> {
+        let url = Url::parse(image_url)?;
+        self.guard.check(&url)?;
+        let host = url.host_str().unwrap_or("images").to_string();
+
+        let resp = self
+            .upstreams
+            .send(&host, self.client.get(url))
+            .await?
+            .error_for_status()?;
+
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+
+        let bytes = Bytes::from(read_capped(resp, self.max_bytes).await?);
+
+        Ok(DownloadedImage {
+            bytes,
+            content_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn max_bytes_test() {
+        let app = Router::new()
+            .route("/small.png", get(|| async { vec![0u8; 64] }))
+            .route("/large.png", get(|| async { vec![0u8; 4096] }));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let guard = FetchGuard {
+            allow_private: true,
+        };
+        let downloader = ImageDownloader::new(Arc::new(Upstreams::default()), guard)
+            .unwrap()
+            .with_max_bytes(1024);
+
+        let small = downloader
+            .download(&format!("http://{addr}/small.png"))
+            .await
+            .unwrap();
+        assert_eq!(64, small.bytes.len());
+
+        let large = downloader
+            .download(&format!("http://{addr}/large.png"))
+            .await;
+        assert!(large
+            .unwrap_err()
+            .to_string()
+            .contains("larger than 1024 bytes"));
+    }
+}
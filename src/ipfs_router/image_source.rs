@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use eyre::{eyre, Result};
+use serde_json::Value;
+
+use crate::AppState;
+
+use super::{
+    seaart_resp::{item_to_payload, SeaArtClient},
+    CreatePayload, IngestPayload,
+};
+
+// An upstream gallery that ingestion can crawl page by page
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Translates an ingest request into the search parameters of this source
+    fn search(&self, query: &IngestPayload) -> Result<Value>;
+
+    async fn page(&self, search: &Value, page: u16) -> Result<Vec<Value>>;
+
+    fn normalize(&self, item: &Value, category: Option<&String>) -> CreatePayload;
+}
+
+#[async_trait]
+impl ImageSource for SeaArtClient {
+    fn name(&self) -> &'static str {
+        "seaart"
+    }
+
+    fn search(&self, query: &IngestPayload) -> Result<Value> {
+        let tags: Vec<&String> = query.tags.iter().collect();
+
+        Ok(self.search_body(&query.q, tags, query.order_by.as_deref()))
+    }
+
+    async fn page(&self, search: &Value, page: u16) -> Result<Vec<Value>> {
+        let mut result = self.get_page(search, page).await?;
+
+        match result["data"]["items"].take() {
+            Value::Array(items) => Ok(items),
+            _ => Err(eyre!("Value not an array")),
+        }
+    }
+
+    fn normalize(&self, item: &Value, category: Option<&String>) -> CreatePayload {
+        item_to_payload(item, category)
+    }
+}
+
+pub fn source_for(
+    state: &AppState,
+    payload: &IngestPayload,
+) -> Result<Arc<dyn ImageSource>, String> {
+    match payload.source.as_deref().unwrap_or("seaart") {
+        "seaart" => Ok(state.seaart.clone()),
+        "html" => Ok(state.html.clone()),
+        other => Err(format!("unknown image source `{other}`")),
+    }
+}
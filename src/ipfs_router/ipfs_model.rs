@@ -1,9 +1,25 @@
 #![allow(dead_code)]
 
 use serde::Serialize;
+use serde_json::{json, Value};
 use sqlx::{
-    types::chrono::{DateTime, Utc},
-    FromRow, Pool, Postgres,
+    postgres::PgRow,
+    types::{
+        chrono::{DateTime, Utc},
+        Json,
+    },
+    FromRow, Pool, Postgres, QueryBuilder, Row,
+};
+
+use super::{
+    categories::ensure_category,
+    content_rating::ContentRating,
+    near_duplicate::phash_to_string,
+    palette::PaletteColor,
+    prompt::parse_prompt,
+    revisions::{lock_tracked_values, record_revision, TrackedValues},
+    variants::ImageVariant,
+    CreatePayload,
 };
 
 #[derive(FromRow, Debug, PartialEq)]
@@ -18,30 +34,144 @@ pub struct SchemaIPFS {
     height: i32,
     prompt: Option<String>,
     hash_id: String,
+    author_id: Option<String>,
+    model_id: Option<String>,
+    channel: Option<String>,
+    num_of_like: i32,
+    num_of_collection: i32,
+    num_of_view: i32,
+    content_rating: String,
+    quarantined: bool,
+    source: String,
+    parsed_prompt: Option<Value>,
+    metadata_width: Option<i32>,
+    metadata_height: Option<i32>,
+    dimensions_mismatch: bool,
+    dimensions_verified_at: Option<DateTime<Utc>>,
+    phash: Option<i64>,
+    near_duplicate_of: Option<i32>,
+    generation_params: Option<Value>,
+    blurhash: Option<String>,
+    average_color: Option<String>,
+    placeholder_attempts: i32,
+    placeholder_failed_at: Option<DateTime<Utc>>,
+}
+
+// A row together with the names of its tags and its variants
+#[derive(Debug)]
+pub struct TaggedIPFS {
+    row: SchemaIPFS,
+    tags: Vec<String>,
+    variants: Vec<ImageVariant>,
+    palette: Vec<PaletteColor>,
+}
+
+impl<'r> FromRow<'r, PgRow> for TaggedIPFS {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        Ok(TaggedIPFS {
+            row: SchemaIPFS::from_row(row)?,
+            tags: row.try_get("tags")?,
+            variants: row.try_get::<Json<Vec<ImageVariant>>, _>("variants")?.0,
+            palette: row.try_get::<Json<Vec<PaletteColor>>, _>("palette")?.0,
+        })
+    }
+}
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct TagFacet {
+    tag: String,
+    count: i64,
+}
+
+// Selected next to `ipfs_image.*` so rows carry their tags
+const TAGS_COLUMN: &str = r#"
+    ARRAY(
+        SELECT tags.name
+        FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = ipfs_image.id
+        ORDER BY tags.name
+    ) AS tags
+"#;
+
+const VARIANTS_COLUMN: &str = r#"
+    COALESCE((
+        SELECT json_agg(json_build_object(
+            'kind', kind, 'format', format, 'width', width, 'height', height, 'url', url
+        ) ORDER BY kind, format, width)
+        FROM image_variants
+        WHERE image_variants.image_id = ipfs_image.id
+    ), '[]') AS variants
+"#;
+
+const PALETTE_COLUMN: &str = r#"
+    COALESCE((
+        SELECT json_agg(json_build_object(
+            'hex', hex, 'l', l, 'a', a, 'b', b, 'share', share
+        ) ORDER BY position)
+        FROM image_colors
+        WHERE image_colors.image_id = ipfs_image.id
+    ), '[]') AS palette
+"#;
+
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    pub source: Option<String>,
+    pub category: Option<String>,
+    pub author_id: Option<String>,
+    pub model_id: Option<String>,
+    pub ratings: Vec<ContentRating>,
+    pub include_quarantined: bool,
+    pub tags: Vec<String>,
+    pub term: Option<String>,
+    pub min_weight: Option<f64>,
+    pub dimensions_mismatch: bool,
+    pub near_duplicates: bool,
+    pub ids: Option<Vec<i32>>,
+    pub sampler: Option<String>,
+    pub model_hash: Option<String>,
+    pub color: Option<ColorFilter>,
+}
+
+// Matches rows with a palette color within `tolerance` delta E of `lab` that covers at
+// least `min_share` of the image
+#[derive(Debug)]
+pub struct ColorFilter {
+    pub lab: [f64; 3],
+    pub tolerance: f64,
+    pub min_share: f64,
 }
 
 pub enum Operation {
-    Create {
-        image: String,
-        ipfs_image_url: String,
-        category: Option<String>,
-        width: i32,
-        height: i32,
-        prompt: Option<String>,
-        hash_id: String,
-    },
+    Create(CreatePayload),
     Read,
     Fetch,
-    Update(i32, Option<String>, Option<String>, Option<String>),
-    Delete(i32),
+    FetchOne(i32),
+    Search(ListFilter),
+    TagFacets(Vec<i32>),
+    ExistingHashIds(String, Vec<String>),
+    // Id, image, ipfs_image_url and category, the actor recorded in the revision, then the
+    // updated_date versions the row has to be at, RowNotFound when it is at none of them
+    Update(
+        i32,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        String,
+        Option<Vec<DateTime<Utc>>>,
+    ),
+    Delete(i32, Option<Vec<DateTime<Utc>>>),
 }
 
 #[derive(Debug)]
 pub enum OperationResult {
     DataStruct(i32, String, String, Option<String>, String),
     UpdateStruct(ReturnJson),
+    Single(ReturnJson),
     ArrStruct(ArrStructData),
     Deleted(i32),
+    HashIds(Vec<String>),
+    Facets(Vec<TagFacet>),
     Error,
 }
 
@@ -54,42 +184,103 @@ pub enum ArrStructData {
 #[derive(Serialize, Debug)]
 pub struct ReturnJson {
     pub id: i32,
-    image: String,
-    ipfs_image_url: String,
+    pub image: String,
+    pub ipfs_image_url: String,
     category: Option<String>,
     created: Option<String>,
-    updated_date: Option<String>,
+    pub updated_date: Option<String>,
+    width: i32,
+    height: i32,
+    metadata_width: Option<i32>,
+    metadata_height: Option<i32>,
+    dimensions_mismatch: bool,
+    dimensions_verified_at: Option<String>,
+    phash: Option<String>,
+    near_duplicate_of: Option<i32>,
+    blurhash: Option<String>,
+    average_color: Option<String>,
+    author_id: Option<String>,
+    model_id: Option<String>,
+    channel: Option<String>,
+    num_of_like: i32,
+    num_of_collection: i32,
+    num_of_view: i32,
+    content_rating: String,
+    quarantined: bool,
+    source: String,
+    tags: Vec<String>,
+    variants: Vec<ImageVariant>,
+    palette: Vec<PaletteColor>,
+    parsed_prompt: Option<Value>,
+    generation_params: Option<Value>,
 }
 
 use OperationResult::*;
 
-fn datetime_to_string(datetime: Option<DateTime<Utc>>) -> Option<String> {
+pub fn datetime_to_string(datetime: Option<DateTime<Utc>>) -> Option<String> {
     datetime.map(|opt| opt.to_rfc3339())
 }
 
+impl ReturnJson {
+    // What the default listing shows, restricted and quarantined rows are only listed on request
+    pub fn is_listed(&self) -> bool {
+        self.content_rating == ContentRating::Safe.as_str() && !self.quarantined
+    }
+}
+
+impl From<SchemaIPFS> for ReturnJson {
+    fn from(row: SchemaIPFS) -> Self {
+        ReturnJson {
+            id: row.id,
+            image: row.image,
+            ipfs_image_url: row.ipfs_image_url,
+            category: row.category,
+            created: datetime_to_string(row.time_created),
+            updated_date: datetime_to_string(row.updated_date),
+            width: row.width,
+            height: row.height,
+            metadata_width: row.metadata_width,
+            metadata_height: row.metadata_height,
+            dimensions_mismatch: row.dimensions_mismatch,
+            dimensions_verified_at: datetime_to_string(row.dimensions_verified_at),
+            phash: phash_to_string(row.phash),
+            near_duplicate_of: row.near_duplicate_of,
+            blurhash: row.blurhash,
+            average_color: row.average_color,
+            author_id: row.author_id,
+            model_id: row.model_id,
+            channel: row.channel,
+            num_of_like: row.num_of_like,
+            num_of_collection: row.num_of_collection,
+            num_of_view: row.num_of_view,
+            content_rating: row.content_rating,
+            quarantined: row.quarantined,
+            source: row.source,
+            tags: Vec::new(),
+            variants: Vec::new(),
+            palette: Vec::new(),
+            parsed_prompt: row.parsed_prompt,
+            generation_params: row.generation_params,
+        }
+    }
+}
+
+impl From<TaggedIPFS> for ReturnJson {
+    fn from(tagged: TaggedIPFS) -> Self {
+        ReturnJson {
+            tags: tagged.tags,
+            variants: tagged.variants,
+            palette: tagged.palette,
+            ..ReturnJson::from(tagged.row)
+        }
+    }
+}
+
 impl Operation {
     pub async fn execute(&self, pool: &Pool<Postgres>) -> Result<OperationResult, sqlx::Error> {
         match self {
-            Self::Create {
-                image,
-                ipfs_image_url,
-                category,
-                width,
-                height,
-                prompt,
-                hash_id,
-            } => {
-                let inserted_data = Self::create_row(
-                    pool,
-                    image,
-                    ipfs_image_url,
-                    category,
-                    width,
-                    height,
-                    prompt,
-                    hash_id,
-                )
-                .await?;
+            Self::Create(payload) => {
+                let inserted_data = Self::create_row(pool, payload).await?;
                 let (id, image, ipfs_image_url, category, hash_id) = inserted_data;
                 Ok(DataStruct(id, image, ipfs_image_url, category, hash_id))
             }
@@ -99,14 +290,16 @@ impl Operation {
                 Ok(ArrStruct(ArrStructData::SchemaEnum(all_data)))
             }
 
-            Self::Update(id, image, ipfs_image_url, category) => {
-                let updated_data = Self::update(pool, *id, image, ipfs_image_url, category).await?;
+            Self::Update(id, image, ipfs_image_url, category, actor, versions) => {
+                let updated_data =
+                    Self::update(pool, *id, image, ipfs_image_url, category, actor, versions)
+                        .await?;
 
                 Ok(UpdateStruct(updated_data))
             }
 
-            Self::Delete(id) => {
-                let id_affected = Self::delete_individual(pool, id).await?;
+            Self::Delete(id, versions) => {
+                let id_affected = Self::delete_individual(pool, id, versions).await?;
                 dbg!(id_affected);
                 Ok(Deleted(id_affected))
             }
@@ -115,37 +308,126 @@ impl Operation {
                 let returned_arr = Self::read_all_ret(pool).await?;
                 Ok(ArrStruct(ArrStructData::ReturnJsonEnum(returned_arr)))
             }
+
+            Self::FetchOne(id) => {
+                let single = Self::fetch_one(pool, *id).await?;
+                Ok(Single(single))
+            }
+
+            Self::Search(filter) => {
+                let returned_arr = Self::search(pool, filter).await?;
+                Ok(ArrStruct(ArrStructData::ReturnJsonEnum(returned_arr)))
+            }
+
+            Self::TagFacets(ids) => {
+                let facets = Self::tag_facets(pool, ids).await?;
+                Ok(Facets(facets))
+            }
+
+            Self::ExistingHashIds(source, hash_ids) => {
+                let existing = Self::existing_hash_ids(pool, source, hash_ids).await?;
+                Ok(HashIds(existing))
+            }
         }
     }
     async fn create_row(
         pool: &Pool<Postgres>,
-        image: &str,
-        ipfs_image_url: &str,
-        category: &Option<String>,
-        w: &i32,
-        h: &i32,
-        prompt: &Option<String>,
-        hash_id: &str,
+        payload: &CreatePayload,
     ) -> Result<(i32, String, String, Option<String>, String), sqlx::Error> {
         let mut tx = pool.begin().await?;
 
+        if let Some(author) = &payload.author {
+            sqlx::query!(
+                r#"
+                    INSERT INTO authors (id, name, avatar)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE
+                    SET name = EXCLUDED.name, avatar = EXCLUDED.avatar, updated_date = NOW()
+                "#,
+                author.id,
+                author.name,
+                author.avatar.as_deref(),
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        if let Some(model_id) = &payload.model_id {
+            sqlx::query!(
+                r#"
+                    INSERT INTO models (id)
+                    VALUES ($1)
+                    ON CONFLICT (id) DO NOTHING
+                "#,
+                model_id,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        let category = ensure_category(&mut tx, payload.category.as_deref()).await?;
+
         let inserted = sqlx::query!(
             r#"
-                INSERT INTO ipfs_image (image, ipfs_image_url, category, width, height, prompt, hash_id)
-                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                INSERT INTO ipfs_image (
+                    image, ipfs_image_url, category, width, height, prompt, hash_id,
+                    author_id, model_id, channel, num_of_like, num_of_collection, num_of_view,
+                    content_rating, quarantined, source, parsed_prompt
+                )
+                VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17
+                )
                 RETURNING id, image, ipfs_image_url, category, hash_id
             "#,
-            image,
-            ipfs_image_url,
-            category.as_deref(),
-            w,
-            h,
-            prompt.as_deref(),
-            hash_id,
+            payload.image,
+            payload.ipfs_image_url,
+            category,
+            payload.width,
+            payload.height,
+            payload.prompt.as_deref(),
+            payload.hash_id,
+            payload.author.as_ref().map(|author| author.id.as_str()),
+            payload.model_id.as_deref(),
+            payload.channel.as_deref(),
+            payload.num_of_like,
+            payload.num_of_collection,
+            payload.num_of_view,
+            payload.content_rating.as_str(),
+            payload.quarantined,
+            payload.source.as_deref().unwrap_or("manual"),
+            payload
+                .prompt
+                .as_deref()
+                .map(|prompt| json!(parse_prompt(prompt))),
         )
         .fetch_one(&mut tx)
         .await?;
 
+        if !payload.tags.is_empty() {
+            sqlx::query!(
+                r#"
+                    INSERT INTO tags (name)
+                    SELECT UNNEST($1::TEXT[])
+                    ON CONFLICT (name) DO NOTHING
+                "#,
+                &payload.tags,
+            )
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                    INSERT INTO image_tags (image_id, tag_id)
+                    SELECT $1, id FROM tags WHERE name = ANY($2)
+                    ON CONFLICT DO NOTHING
+                "#,
+                inserted.id,
+                &payload.tags,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
         tx.commit().await?;
 
         let (id, image, ipfs_image_url, category, hash_id) = (
@@ -175,41 +457,236 @@ impl Operation {
     }
 
     async fn read_all_ret(pool: &Pool<Postgres>) -> Result<Vec<ReturnJson>, sqlx::Error> {
-        let all_data: Vec<_> = sqlx::query!(
-            r#"
-            SELECT id, image, time_created, ipfs_image_url, category, updated_date
-            FROM ipfs_image
-            "#
-        )
+        let all_data = sqlx::query_as::<_, TaggedIPFS>(&format!(
+            "SELECT ipfs_image.*, {TAGS_COLUMN}, {VARIANTS_COLUMN}, {PALETTE_COLUMN} FROM ipfs_image"
+        ))
         .fetch_all(pool)
         .await?;
         dbg!(&all_data);
 
-        let mapped_data = all_data
-            .iter()
-            .map(|elm| ReturnJson {
-                id: elm.id,
-                image: elm.image.to_owned(),
-                ipfs_image_url: elm.ipfs_image_url.to_owned(),
-                category: elm.category.to_owned(),
-                created: datetime_to_string(elm.time_created.to_owned()),
-                updated_date: datetime_to_string(elm.updated_date.to_owned()),
-            })
-            .collect();
+        let mapped_data = all_data.into_iter().map(ReturnJson::from).collect();
 
         Ok(mapped_data)
     }
 
+    async fn fetch_one(pool: &Pool<Postgres>, id: i32) -> Result<ReturnJson, sqlx::Error> {
+        let single = sqlx::query_as::<_, TaggedIPFS>(&format!(
+            "SELECT ipfs_image.*, {TAGS_COLUMN}, {VARIANTS_COLUMN}, {PALETTE_COLUMN} FROM ipfs_image WHERE id = $1"
+        ))
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(ReturnJson::from(single))
+    }
+
+    async fn search(
+        pool: &Pool<Postgres>,
+        filter: &ListFilter,
+    ) -> Result<Vec<ReturnJson>, sqlx::Error> {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "SELECT ipfs_image.*, {TAGS_COLUMN}, {VARIANTS_COLUMN}, {PALETTE_COLUMN} FROM ipfs_image WHERE TRUE"
+        ));
+
+        if let Some(source) = &filter.source {
+            query.push(" AND source = ").push_bind(source);
+        }
+
+        // The category or any of its subcategories
+        if let Some(category) = &filter.category {
+            query
+                .push(
+                    " AND category IN (
+                        WITH RECURSIVE tree (id, slug) AS (
+                            SELECT id, slug FROM categories WHERE slug = ",
+                )
+                .push_bind(category)
+                .push(
+                    "
+                            UNION
+                            SELECT child.id, child.slug
+                            FROM categories child
+                            JOIN tree ON child.parent_id = tree.id
+                        )
+                        SELECT slug FROM tree
+                    )",
+                );
+        }
+
+        if let Some(author_id) = &filter.author_id {
+            query.push(" AND author_id = ").push_bind(author_id);
+        }
+
+        if let Some(model_id) = &filter.model_id {
+            query.push(" AND model_id = ").push_bind(model_id);
+        }
+
+        // Restricted ratings and quarantined rows stay hidden unless asked for
+        let ratings: Vec<&str> = if filter.ratings.is_empty() {
+            vec![ContentRating::Safe.as_str()]
+        } else {
+            filter.ratings.iter().map(ContentRating::as_str).collect()
+        };
+        query
+            .push(" AND content_rating = ANY(")
+            .push_bind(ratings)
+            .push(")");
+
+        if !filter.include_quarantined {
+            query.push(" AND NOT quarantined");
+        }
+
+        if filter.dimensions_mismatch {
+            query.push(" AND dimensions_mismatch");
+        }
+
+        if let Some(sampler) = &filter.sampler {
+            query
+                .push(" AND generation_params->>'sampler' = ")
+                .push_bind(sampler);
+        }
+
+        if let Some(model_hash) = &filter.model_hash {
+            query
+                .push(" AND generation_params->>'model_hash' = ")
+                .push_bind(model_hash);
+        }
+
+        if let Some(color) = &filter.color {
+            let [l, a, b] = color.lab;
+            query
+                .push(
+                    " AND EXISTS (SELECT 1 FROM image_colors \
+                     WHERE image_colors.image_id = ipfs_image.id AND share >= ",
+                )
+                .push_bind(color.min_share)
+                .push(" AND SQRT(POWER(l - ")
+                .push_bind(l)
+                .push(", 2) + POWER(a - ")
+                .push_bind(a)
+                .push(", 2) + POWER(b - ")
+                .push_bind(b)
+                .push(", 2)) <= ")
+                .push_bind(color.tolerance)
+                .push(")");
+        }
+
+        if let Some(ids) = &filter.ids {
+            query.push(" AND id = ANY(").push_bind(ids).push(")");
+        }
+
+        if filter.near_duplicates {
+            query.push(" AND near_duplicate_of IS NOT NULL");
+        }
+
+        // A term of the parsed prompt, optionally with at least the given attention weight
+        if filter.term.is_some() || filter.min_weight.is_some() {
+            query.push(
+                " AND EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(parsed_prompt->'terms') AS term
+                    WHERE TRUE",
+            );
+
+            if let Some(term) = &filter.term {
+                query
+                    .push(" AND LOWER(term->>'text') = LOWER(")
+                    .push_bind(term)
+                    .push(")");
+            }
+
+            if let Some(min_weight) = filter.min_weight {
+                query
+                    .push(" AND (term->>'weight')::FLOAT8 >= ")
+                    .push_bind(min_weight);
+            }
+
+            query.push(")");
+        }
+
+        // Every requested tag has to be on the row
+        if !filter.tags.is_empty() {
+            query
+                .push(
+                    " AND id IN (
+                        SELECT image_tags.image_id
+                        FROM image_tags
+                        JOIN tags ON tags.id = image_tags.tag_id
+                        WHERE tags.name = ANY(",
+                )
+                .push_bind(&filter.tags)
+                .push(
+                    ")
+                        GROUP BY image_tags.image_id
+                        HAVING COUNT(*) = ",
+                )
+                .push_bind(filter.tags.len() as i64)
+                .push(")");
+        }
+
+        query.push(" ORDER BY id");
+
+        let all_data = query.build_query_as::<TaggedIPFS>().fetch_all(pool).await?;
+
+        let mapped_data = all_data.into_iter().map(ReturnJson::from).collect();
+
+        Ok(mapped_data)
+    }
+
+    async fn tag_facets(pool: &Pool<Postgres>, ids: &[i32]) -> Result<Vec<TagFacet>, sqlx::Error> {
+        let facets = sqlx::query_as!(
+            TagFacet,
+            r#"
+            SELECT tags.name AS tag, COUNT(*) AS "count!"
+            FROM image_tags
+            JOIN tags ON tags.id = image_tags.tag_id
+            WHERE image_tags.image_id = ANY($1)
+            GROUP BY tags.name
+            ORDER BY 2 DESC, tags.name
+            "#,
+            ids,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(facets)
+    }
+
+    async fn existing_hash_ids(
+        pool: &Pool<Postgres>,
+        source: &str,
+        hash_ids: &[String],
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let existing = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT hash_id
+            FROM ipfs_image
+            WHERE source = $1 AND hash_id = ANY($2)
+            "#,
+            source,
+            hash_ids,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(existing)
+    }
+
     async fn update(
         pool: &Pool<Postgres>,
         id: i32,
         image: &Option<String>,
         ipfs_image_url: &Option<String>,
         category: &Option<String>,
+        actor: &str,
+        versions: &Option<Vec<DateTime<Utc>>>,
     ) -> Result<ReturnJson, sqlx::Error> {
         let mut tx = pool.begin().await?;
 
-        let updated_res = sqlx::query!(
+        let category = ensure_category(&mut tx, category.as_deref()).await?;
+        let old = lock_tracked_values(&mut tx, id).await?;
+
+        let updated_res = sqlx::query_as!(
+            SchemaIPFS,
             r#"
                 UPDATE ipfs_image
                 SET 
@@ -217,40 +694,88 @@ impl Operation {
                     ipfs_image_url = COALESCE($2, ipfs_image_url),
                     category = COALESCE($3, category),
                     updated_date = NOW()
-                WHERE id = $4
-                RETURNING id, image, time_created, ipfs_image_url, category, updated_date
+                WHERE id = $4 AND ($5::TIMESTAMPTZ[] IS NULL OR updated_date = ANY($5))
+                RETURNING *
             "#,
             image.as_deref(),
             ipfs_image_url.as_deref(),
-            category.as_deref(),
+            category,
             id,
+            versions.as_deref(),
         )
         .fetch_one(&mut tx)
         .await?;
 
-        tx.commit().await?;
-
-        let updated_res_data = ReturnJson {
-            id: updated_res.id,
-            image: updated_res.image,
-            ipfs_image_url: updated_res.ipfs_image_url,
-            category: updated_res.category,
-            created: datetime_to_string(updated_res.time_created),
-            updated_date: datetime_to_string(updated_res.updated_date),
+        let new = TrackedValues {
+            image: updated_res.image.clone(),
+            ipfs_image_url: updated_res.ipfs_image_url.clone(),
+            category: updated_res.category.clone(),
         };
+        record_revision(&mut tx, id, &old, &new, actor, None).await?;
+
+        let tags = sqlx::query_scalar!(
+            r#"
+                SELECT tags.name
+                FROM image_tags
+                JOIN tags ON tags.id = image_tags.tag_id
+                WHERE image_tags.image_id = $1
+                ORDER BY tags.name
+            "#,
+            id,
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        let variants = sqlx::query_as!(
+            ImageVariant,
+            r#"
+                SELECT kind, format, width, height, url
+                FROM image_variants
+                WHERE image_id = $1
+                ORDER BY kind, format, width
+            "#,
+            id,
+        )
+        .fetch_all(&mut tx)
+        .await?;
 
-        Ok(updated_res_data)
+        let palette = sqlx::query_as!(
+            PaletteColor,
+            r#"
+                SELECT hex, l, a, b, share
+                FROM image_colors
+                WHERE image_id = $1
+                ORDER BY position
+            "#,
+            id,
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(ReturnJson {
+            tags,
+            variants,
+            palette,
+            ..ReturnJson::from(updated_res)
+        })
     }
 
-    async fn delete_individual(pool: &Pool<Postgres>, id: &i32) -> Result<i32, sqlx::Error> {
+    async fn delete_individual(
+        pool: &Pool<Postgres>,
+        id: &i32,
+        versions: &Option<Vec<DateTime<Utc>>>,
+    ) -> Result<i32, sqlx::Error> {
         let mut tx = pool.begin().await?;
         let stmt = sqlx::query(
             r#"
             DELETE FROM ipfs_image
-            WHERE id = $1
+            WHERE id = $1 AND ($2::TIMESTAMPTZ[] IS NULL OR updated_date = ANY($2))
             "#,
         )
         .bind(id)
+        .bind(versions)
         .execute(&mut tx)
         .await?;
         tx.commit().await?;
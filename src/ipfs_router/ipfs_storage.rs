@@ -0,0 +1,76 @@
+use std::{env, sync::Arc, time::Duration};
+
+use eyre::{eyre, Result};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde_json::Value;
+
+use super::{image_download::DownloadedImage, seaart_resp::USER_AGENT, upstream::Upstreams};
+
+const DEFAULT_STORAGE_URL: &str = "https://api.nft.storage";
+const IPFS_GATEWAY: &str = "https://ipfs.io/ipfs";
+const STORAGE_UPSTREAM: &str = "nft.storage";
+
+// Pins images to IPFS through nft.storage
+#[derive(Debug, Clone)]
+pub struct IpfsStorage {
+    client: Client,
+    base_url: String,
+    token: Option<String>,
+    upstreams: Arc<Upstreams>,
+}
+
+impl IpfsStorage {
+    // IPFS_STORAGE holds the API token, IPFS_STORAGE_URL overrides the endpoint
+    pub fn from_env(upstreams: Arc<Upstreams>) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        let base_url =
+            env::var("IPFS_STORAGE_URL").unwrap_or_else(|_| DEFAULT_STORAGE_URL.to_string());
+
+        upstreams.register(STORAGE_UPSTREAM);
+
+        Ok(IpfsStorage {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: env::var("IPFS_STORAGE")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            upstreams,
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.token.is_some()
+    }
+
+    // Uploads a downloaded image and returns its gateway URL
+    pub async fn pin(&self, image: &DownloadedImage) -> Result<String> {
+        let token = self
+            .token
+            .as_deref()
+            .ok_or_else(|| eyre!("IPFS_STORAGE is not set"))?;
+
+        let request = self
+            .client
+            .post(format!("{}/upload", self.base_url))
+            .bearer_auth(token)
+            .header(CONTENT_TYPE, &image.content_type)
+            .body(image.bytes.clone());
+
+        let resp: Value = self
+            .upstreams
+            .send(STORAGE_UPSTREAM, request)
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let cid = resp["value"]["cid"]
+            .as_str()
+            .ok_or_else(|| eyre!("nft.storage response has no cid"))?;
+
+        Ok(format!("{IPFS_GATEWAY}/{cid}"))
+    }
+}
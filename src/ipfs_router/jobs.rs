@@ -0,0 +1,360 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use tokio::task::JoinError;
+
+use crate::AppState;
+
+use super::{
+    crawl::{crawl, CrawlReport, PageOutcome},
+    ipfs_model::datetime_to_string,
+    IngestPayload,
+};
+
+// Cancellation flags of the jobs running in this process
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    running: Mutex<HashMap<i32, Arc<AtomicBool>>>,
+}
+
+impl JobRegistry {
+    pub fn register(&self, id: i32) -> JobHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.running
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&cancelled));
+
+        JobHandle { id, cancelled }
+    }
+
+    fn unregister(&self, id: i32) {
+        self.running.lock().unwrap().remove(&id);
+    }
+
+    pub fn is_running(&self, id: i32) -> bool {
+        self.running.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn cancel(&self, id: i32) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct JobHandle {
+    pub id: i32,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub async fn record_page(
+        &self,
+        pool: &Pool<Postgres>,
+        page: u16,
+        outcome: &PageOutcome,
+    ) -> Result<(), sqlx::Error> {
+        let errors = json!(outcome.errors);
+
+        sqlx::query!(
+            r#"
+            UPDATE ingest_jobs
+            SET
+                pages_crawled = pages_crawled + 1,
+                last_page = $2,
+                total_inserted = total_inserted + $3,
+                total_duplicate = total_duplicate + $4,
+                total_failure = total_failure + $5,
+                total_skipped = total_skipped + $6,
+                total_quarantined = total_quarantined + $7,
+                total_near_duplicate = total_near_duplicate + $8,
+                errors = errors || $9
+            WHERE id = $1
+            "#,
+            self.id,
+            page as i32,
+            outcome.inserted as i32,
+            outcome.duplicate as i32,
+            outcome.failure as i32,
+            outcome.skipped as i32,
+            outcome.quarantined as i32,
+            outcome.near_duplicate as i32,
+            errors,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestJob {
+    pub id: i32,
+    params: Value,
+    pub status: String,
+    pages_crawled: i32,
+    last_page: Option<i32>,
+    total_inserted: i32,
+    total_duplicate: i32,
+    total_failure: i32,
+    total_skipped: i32,
+    total_quarantined: i32,
+    total_near_duplicate: i32,
+    errors: Value,
+    stop_reason: Option<String>,
+    error_message: Option<String>,
+    created: Option<String>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+}
+
+// How often the final status of a job is written before giving up on it
+const FINISH_ATTEMPTS: u64 = 3;
+
+pub async fn start_job(state: &AppState, payload: IngestPayload) -> Result<i32, sqlx::Error> {
+    let params = json!(payload);
+
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO ingest_jobs (params)
+        VALUES ($1)
+        RETURNING id
+        "#,
+        params,
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let handle = state.jobs.register(id);
+    let state = state.clone();
+
+    tokio::spawn(async move {
+        // The crawl runs in a task of its own, so a panic in it still ends up as a failed job
+        let job_state = state.clone();
+        let result =
+            tokio::spawn(async move { run_job(&job_state, &payload, &handle).await }).await;
+
+        finish_job(&state.pool, id, &JobStatus::from_result(result)).await;
+        state.jobs.unregister(id);
+    });
+
+    Ok(id)
+}
+
+async fn run_job(
+    state: &AppState,
+    payload: &IngestPayload,
+    handle: &JobHandle,
+) -> eyre::Result<CrawlReport> {
+    sqlx::query!(
+        r#"
+        UPDATE ingest_jobs
+        SET status = 'running', started_at = NOW()
+        WHERE id = $1
+        "#,
+        handle.id,
+    )
+    .execute(&state.pool)
+    .await?;
+
+    crawl(state, payload, handle).await
+}
+
+// What a job ended with, written once when its task is done
+#[derive(Debug, PartialEq)]
+struct JobStatus {
+    status: &'static str,
+    stop_reason: &'static str,
+    error_message: Option<String>,
+}
+
+impl JobStatus {
+    fn from_result(result: Result<eyre::Result<CrawlReport>, JoinError>) -> Self {
+        let (status, stop_reason, error_message) = match result {
+            Ok(Ok(report)) if report.stop_reason == "cancelled" => {
+                ("cancelled", report.stop_reason, None)
+            }
+            Ok(Ok(report)) => ("finished", report.stop_reason, None),
+            Ok(Err(err)) => ("failed", "error", Some(err.to_string())),
+            Err(err) if err.is_panic() => {
+                let panic = err.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "job panicked".to_string());
+                ("failed", "panicked", Some(message))
+            }
+            Err(err) => ("failed", "error", Some(err.to_string())),
+        };
+
+        JobStatus {
+            status,
+            stop_reason,
+            error_message,
+        }
+    }
+}
+
+// A job whose status can not be written stays `running` until fail_interrupted_jobs at restart
+async fn finish_job(pool: &Pool<Postgres>, id: i32, status: &JobStatus) {
+    for attempt in 1..=FINISH_ATTEMPTS {
+        let res = sqlx::query!(
+            r#"
+            UPDATE ingest_jobs
+            SET status = $2, stop_reason = $3, error_message = $4, finished_at = NOW()
+            WHERE id = $1
+            "#,
+            id,
+            status.status,
+            status.stop_reason,
+            status.error_message,
+        )
+        .execute(pool)
+        .await;
+
+        match res {
+            Ok(_) => return,
+            Err(err) => {
+                eprintln!("Writing the status of ingest job {id} failed, attempt {attempt}: {err}");
+                if attempt < FINISH_ATTEMPTS {
+                    tokio::time::sleep(Duration::from_secs(attempt)).await;
+                }
+            }
+        }
+    }
+}
+
+pub async fn fetch_job(pool: &Pool<Postgres>, id: i32) -> Result<IngestJob, sqlx::Error> {
+    let job = sqlx::query!(
+        r#"
+        SELECT *
+        FROM ingest_jobs
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(IngestJob {
+        id: job.id,
+        params: job.params,
+        status: job.status,
+        pages_crawled: job.pages_crawled,
+        last_page: job.last_page,
+        total_inserted: job.total_inserted,
+        total_duplicate: job.total_duplicate,
+        total_failure: job.total_failure,
+        total_skipped: job.total_skipped,
+        total_quarantined: job.total_quarantined,
+        total_near_duplicate: job.total_near_duplicate,
+        errors: job.errors,
+        stop_reason: job.stop_reason,
+        error_message: job.error_message,
+        created: datetime_to_string(job.time_created),
+        started_at: datetime_to_string(job.started_at),
+        finished_at: datetime_to_string(job.finished_at),
+    })
+}
+
+// Jobs are driven by tasks in this process, anything still open after a restart was interrupted
+pub async fn fail_interrupted_jobs(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+        UPDATE ingest_jobs
+        SET status = 'failed', stop_reason = 'interrupted', finished_at = NOW()
+        WHERE status IN ('queued', 'running')
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod test {
+    use eyre::eyre;
+
+    use super::*;
+
+    fn report(stop_reason: &'static str) -> CrawlReport {
+        CrawlReport {
+            stop_reason,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cancel_test() {
+        let jobs = JobRegistry::default();
+        let handle = jobs.register(4);
+
+        assert!(jobs.is_running(4));
+        assert!(!handle.is_cancelled());
+        assert!(!jobs.cancel(5));
+
+        assert!(jobs.cancel(4));
+        assert!(handle.is_cancelled());
+
+        jobs.unregister(4);
+        assert!(!jobs.is_running(4));
+        assert!(!jobs.cancel(4));
+    }
+
+    #[tokio::test]
+    async fn status_test() {
+        let status = JobStatus::from_result(Ok(Ok(report("no_items"))));
+        assert_eq!(
+            ("finished", "no_items"),
+            (status.status, status.stop_reason)
+        );
+
+        let status = JobStatus::from_result(Ok(Ok(report("cancelled"))));
+        assert_eq!(
+            ("cancelled", "cancelled"),
+            (status.status, status.stop_reason)
+        );
+
+        let status = JobStatus::from_result(Ok(Err(eyre!("upstream down"))));
+        assert_eq!(
+            JobStatus {
+                status: "failed",
+                stop_reason: "error",
+                error_message: Some("upstream down".to_string()),
+            },
+            status
+        );
+
+        let panicked = tokio::spawn(async { panic!("bad page") }).await;
+        let status = JobStatus::from_result(panicked.map(|()| Ok(report(""))));
+        assert_eq!(
+            JobStatus {
+                status: "failed",
+                stop_reason: "panicked",
+                error_message: Some("bad page".to_string()),
+            },
+            status
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    str::FromStr,
+};
+
+use eyre::Result;
+
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use super::similar::{BkTree, SimilarIndex};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAction {
+    Allow,
+    Flag,
+    Skip,
+}
+
+impl FromStr for DuplicateAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "flag" => Ok(Self::Flag),
+            "skip" => Ok(Self::Skip),
+            other => Err(format!("unknown near-duplicate action `{other}`")),
+        }
+    }
+}
+
+// What ingestion does with an image whose hash is within `max_distance` bits of a stored one
+#[derive(Debug, Clone)]
+pub struct DuplicatePolicy {
+    pub action: DuplicateAction,
+    pub max_distance: u32,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        DuplicatePolicy {
+            action: DuplicateAction::Flag,
+            max_distance: 6,
+        }
+    }
+}
+
+impl DuplicatePolicy {
+    // NEAR_DUPLICATE_ACTION and NEAR_DUPLICATE_DISTANCE override the defaults
+    pub fn from_env() -> Self {
+        let mut policy = DuplicatePolicy::default();
+
+        if let Ok(action) = env::var("NEAR_DUPLICATE_ACTION") {
+            policy.action = action.parse().expect("NEAR_DUPLICATE_ACTION is invalid");
+        }
+
+        if let Ok(distance) = env::var("NEAR_DUPLICATE_DISTANCE") {
+            policy.max_distance = distance
+                .parse()
+                .expect("NEAR_DUPLICATE_DISTANCE is invalid");
+        }
+
+        policy
+    }
+}
+
+// Difference hash: one bit per neighbouring pixel pair of a 9x8 grayscale thumbnail
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let thumb = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumb.get_pixel(x, y)[0] > thumb.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub fn phash_to_string(phash: Option<i64>) -> Option<String> {
+    phash.map(|phash| format!("{:016x}", phash as u64))
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClusterMember {
+    id: i32,
+    image: String,
+    hash_id: String,
+    source: String,
+    phash: Option<String>,
+    // Bits differing from the first member of the cluster
+    distance: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DuplicateCluster {
+    size: usize,
+    members: Vec<ClusterMember>,
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Groups hashes that are linked by chains of near-duplicate pairs, returns the member indexes.
+// Neighbours come from a BK-tree, so this stays far from comparing every pair.
+pub fn cluster_hashes(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut tree = BkTree::default();
+    for (i, &hash) in hashes.iter().enumerate() {
+        tree.insert(hash, i as i32);
+    }
+
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+
+    for (i, &hash) in hashes.iter().enumerate() {
+        for (j, ..) in tree.find(hash, max_distance) {
+            let (a, b) = (
+                find_root(&mut parents, i),
+                find_root(&mut parents, j as usize),
+            );
+            parents[a.max(b)] = a.min(b);
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..hashes.len() {
+        let root = find_root(&mut parents, i);
+        clusters.entry(root).or_default().push(i);
+    }
+
+    let mut clusters: Vec<Vec<usize>> = clusters
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .collect();
+    clusters.sort_by_key(|members| std::cmp::Reverse(members.len()));
+
+    clusters
+}
+
+// Largest clusters first, only the rows of the requested page are loaded
+pub async fn duplicate_clusters(
+    pool: &Pool<Postgres>,
+    index: &SimilarIndex,
+    max_distance: u32,
+    page: usize,
+    per_page: usize,
+) -> Result<(usize, Vec<DuplicateCluster>)> {
+    let entries = index.entries();
+    let hashes: Vec<u64> = entries.iter().map(|&(_, hash)| hash).collect();
+
+    // Clustering the whole index is CPU bound, keep it off the async workers
+    let clusters =
+        tokio::task::spawn_blocking(move || cluster_hashes(&hashes, max_distance)).await?;
+    let total = clusters.len();
+
+    let clusters: Vec<Vec<(i32, u64)>> = clusters
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .map(|members| members.into_iter().map(|i| entries[i]).collect())
+        .collect();
+
+    let ids: Vec<i32> = clusters.iter().flatten().map(|&(id, _)| id).collect();
+    let rows: HashMap<i32, _> = sqlx::query!(
+        r#"
+        SELECT id, image, hash_id, source
+        FROM ipfs_image
+        WHERE id = ANY($1)
+        "#,
+        &ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, row))
+    .collect();
+
+    let clusters = clusters
+        .into_iter()
+        .map(|members| {
+            let first = members[0].1;
+            // Rows deleted since the index was read drop out
+            let members: Vec<ClusterMember> = members
+                .into_iter()
+                .filter_map(|(id, hash)| {
+                    let row = rows.get(&id)?;
+
+                    Some(ClusterMember {
+                        id,
+                        image: row.image.clone(),
+                        hash_id: row.hash_id.clone(),
+                        source: row.source.clone(),
+                        phash: phash_to_string(Some(hash as i64)),
+                        distance: hamming(first, hash),
+                    })
+                })
+                .collect();
+
+            DuplicateCluster {
+                size: members.len(),
+                members,
+            }
+        })
+        .collect();
+
+    Ok((total, clusters))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32, mirror: bool) -> DynamicImage {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            let x = if mirror { width - 1 - x } else { x };
+            let v = (x * 200 / width + y * 50 / height) as u8;
+            Rgb([v, v / 2, 255 - v])
+        });
+
+        DynamicImage::ImageRgb8(img)
+    }
+
+    #[test]
+    fn dhash_test() {
+        let original = dhash(&gradient(512, 384, false));
+        let resized = dhash(&gradient(400, 300, false));
+        let other = dhash(&gradient(512, 384, true));
+
+        assert!(hamming(original, resized) <= 6);
+        assert!(hamming(original, other) > 6);
+    }
+
+    #[test]
+    fn cluster_test() {
+        let hashes = [
+            0b0000,
+            0xffff_0000,
+            0b0011,
+            0b0111,
+            0xffff_0001,
+            0xf0f0_f0f0_f0f0,
+        ];
+
+        assert_eq!(vec![vec![0, 2, 3], vec![1, 4]], cluster_hashes(&hashes, 2));
+    }
+}
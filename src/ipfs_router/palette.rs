@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+
+const PALETTE_SIZE: usize = 5;
+const KMEANS_ROUNDS: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PaletteColor {
+    pub hex: String,
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+    // Fraction of the pixels closest to this color
+    pub share: f64,
+}
+
+// An 8 bit sRGB channel as linear light from 0 to 1
+pub fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// CIE L*a*b* of an sRGB color, D65 white
+pub fn rgb_to_lab([r, g, b]: [u8; 3]) -> [f64; 3] {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// CIE76 delta E, around 2.3 is the smallest difference people notice
+pub fn delta_e(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+// `#ff8800`, `ff8800` or `#f80`
+pub fn parse_hex(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().trim_start_matches('#');
+    if !hex.is_ascii() {
+        return None;
+    }
+
+    let hex = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+// Pixels of one coarse RGB histogram cell
+#[derive(Debug, Default)]
+struct Cell {
+    count: usize,
+    sum: [u64; 3],
+}
+
+// K-means in Lab over a thumbnail, seeded with the busiest cells of a coarse RGB histogram
+pub fn extract_palette(img: &DynamicImage) -> Vec<PaletteColor> {
+    let thumb = img.resize(64, 64, FilterType::Triangle).to_rgb8();
+    let pixels: Vec<[u8; 3]> = thumb.pixels().map(|pixel| pixel.0).collect();
+
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let mut cells: HashMap<[u8; 3], Cell> = HashMap::new();
+    for pixel in &pixels {
+        let cell = cells.entry(pixel.map(|c| c >> 5)).or_default();
+        cell.count += 1;
+        for (sum, c) in cell.sum.iter_mut().zip(pixel) {
+            *sum += *c as u64;
+        }
+    }
+
+    let mut cells: Vec<([u8; 3], Cell)> = cells.into_iter().collect();
+    cells.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+
+    let mut centers: Vec<[f64; 3]> = cells
+        .iter()
+        .take(PALETTE_SIZE)
+        .map(|(_, cell)| rgb_to_lab(cell.sum.map(|c| (c / cell.count as u64) as u8)))
+        .collect();
+
+    let labs: Vec<[f64; 3]> = pixels.iter().map(|&pixel| rgb_to_lab(pixel)).collect();
+    let mut assigned = vec![0; labs.len()];
+
+    for _ in 0..KMEANS_ROUNDS {
+        for (lab, assigned) in labs.iter().zip(assigned.iter_mut()) {
+            *assigned = nearest(&centers, *lab);
+        }
+
+        let mut sums = vec![([0.0; 3], 0usize); centers.len()];
+        for (lab, &center) in labs.iter().zip(&assigned) {
+            for (sum, c) in sums[center].0.iter_mut().zip(lab) {
+                *sum += c;
+            }
+            sums[center].1 += 1;
+        }
+
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = sum.map(|c| c / count as f64);
+            }
+        }
+    }
+
+    let mut counts = vec![0usize; centers.len()];
+    for lab in &labs {
+        counts[nearest(&centers, *lab)] += 1;
+    }
+
+    let mut palette: Vec<PaletteColor> = centers
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(lab, count)| PaletteColor {
+            hex: closest_hex(&pixels, &labs, lab),
+            l: round(lab[0]),
+            a: round(lab[1]),
+            b: round(lab[2]),
+            share: round(count as f64 / labs.len() as f64),
+        })
+        .collect();
+    palette.sort_by(|a, b| b.share.total_cmp(&a.share));
+
+    palette
+}
+
+fn nearest(centers: &[[f64; 3]], lab: [f64; 3]) -> usize {
+    (0..centers.len())
+        .min_by(|&a, &b| delta_e(centers[a], lab).total_cmp(&delta_e(centers[b], lab)))
+        .unwrap_or(0)
+}
+
+// The hex of the real pixel closest to a center, averaged Lab may not map back to sRGB
+fn closest_hex(pixels: &[[u8; 3]], labs: &[[f64; 3]], center: [f64; 3]) -> String {
+    let [r, g, b] = pixels
+        .iter()
+        .zip(labs)
+        .min_by(|a, b| delta_e(*a.1, center).total_cmp(&delta_e(*b.1, center)))
+        .map(|(pixel, _)| *pixel)
+        .unwrap_or_default();
+
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn palette_test() {
+        // Three quarters orange, one quarter navy
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(200, 100, |x, _| {
+            if x < 150 {
+                Rgb([255, 136, 0])
+            } else {
+                Rgb([0, 0, 128])
+            }
+        }));
+
+        let palette = extract_palette(&img);
+        assert_eq!("#ff8800", palette[0].hex);
+        assert!((palette[0].share - 0.75).abs() < 0.05);
+        assert!(palette.iter().any(|color| color.hex == "#000080"));
+
+        let orange = rgb_to_lab(parse_hex("#f80").unwrap());
+        let first = [palette[0].l, palette[0].a, palette[0].b];
+        assert!(delta_e(orange, first) < 5.0);
+        assert!(delta_e(orange, rgb_to_lab([0, 0, 128])) > 50.0);
+
+        assert_eq!(Some([255, 136, 0]), parse_hex("ff8800"));
+        assert_eq!(None, parse_hex("#ff88"));
+        assert_eq!(None, parse_hex("#gg8800"));
+        assert_eq!(None, parse_hex("€abc"));
+    }
+}
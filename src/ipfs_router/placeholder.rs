@@ -0,0 +1,89 @@
+use eyre::Result;
+use image::{imageops::FilterType, DynamicImage};
+
+use super::palette::srgb_to_linear;
+
+// Blurhash only keeps low frequencies, a small thumbnail gives the same hash much faster
+const THUMB_SIZE: u32 = 32;
+
+// What the front end shows while the image itself loads
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placeholder {
+    pub blurhash: String,
+    pub average_color: String,
+}
+
+pub fn placeholder(img: &DynamicImage) -> Result<Placeholder> {
+    let thumb = img
+        .resize(THUMB_SIZE, THUMB_SIZE, FilterType::Triangle)
+        .to_rgba8();
+
+    // 4x3 components for landscape images, 3x4 for portrait ones
+    let (components_x, components_y) = if thumb.width() >= thumb.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        thumb.width(),
+        thumb.height(),
+        thumb.as_raw(),
+    )?;
+
+    Ok(Placeholder {
+        blurhash,
+        average_color: average_color(thumb.pixels().map(|pixel| [pixel[0], pixel[1], pixel[2]])),
+    })
+}
+
+// Averaged in linear light, so a black and white checkerboard comes out mid grey rather than dark
+fn average_color(pixels: impl Iterator<Item = [u8; 3]>) -> String {
+    let to_srgb = |c: f64| {
+        let c = if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+
+    let mut sum = [0.0; 3];
+    let mut count = 0;
+    for pixel in pixels {
+        for (sum, c) in sum.iter_mut().zip(pixel) {
+            *sum += srgb_to_linear(c);
+        }
+        count += 1;
+    }
+
+    let [r, g, b] = sum.map(|c| to_srgb(c / count.max(1) as f64));
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn placeholder_test() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(60, 40, Rgb([255, 136, 0])));
+        let orange = placeholder(&img).unwrap();
+
+        assert_eq!("#ff8800", orange.average_color);
+        // One size character, one max AC, four DC and two per AC component
+        assert_eq!(1 + 1 + 4 + 2 * 11, orange.blurhash.len());
+
+        let portrait = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 60, Rgb([0, 0, 0])));
+        assert_eq!(
+            placeholder(&img).unwrap().blurhash.len(),
+            placeholder(&portrait).unwrap().blurhash.len()
+        );
+
+        let checker = (0..100).map(|i| if i % 2 == 0 { [0; 3] } else { [255; 3] });
+        assert_eq!("#bcbcbc", average_color(checker));
+    }
+}
@@ -0,0 +1,126 @@
+use std::env;
+
+use axum::http::{
+    header::{IF_MATCH, IF_NONE_MATCH},
+    HeaderMap,
+};
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::render::digest;
+
+// Whether PATCH and DELETE of image rows have to send If-Match
+#[derive(Debug, Clone, Default)]
+pub struct PreconditionPolicy {
+    pub require_if_match: bool,
+}
+
+impl PreconditionPolicy {
+    // REQUIRE_IF_MATCH=true answers 428 to writes without If-Match, they are let through otherwise
+    pub fn from_env() -> Self {
+        PreconditionPolicy {
+            require_if_match: env::var("REQUIRE_IF_MATCH").is_ok_and(|val| val == "true"),
+        }
+    }
+}
+
+// `"<updated_date micros>-<body digest>"`. Analysis, variants, tags and category renames
+// change the body without moving updated_date, the digest makes reads see those. Only the
+// updated_date part is what If-Match compares, it is what the guarded writes move.
+pub fn row_etag(updated_date: Option<&str>, body: &impl Serialize) -> String {
+    let micros = updated_date
+        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        .map_or(0, |date| date.timestamp_micros());
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    format!("\"{micros}-{}\"", digest(body))
+}
+
+fn tag_version(tag: &str) -> Option<DateTime<Utc>> {
+    let tag = tag.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let micros = tag
+        .split_once('-')
+        .map_or(tag, |(micros, _)| micros)
+        .parse()
+        .ok()?;
+    NaiveDateTime::from_timestamp_micros(micros).map(|date| DateTime::from_utc(date, Utc))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IfMatch {
+    // `*`, which any existing row matches
+    Any,
+    // The versions the client has seen, weak and unknown tags never match
+    Versions(Vec<DateTime<Utc>>),
+}
+
+impl IfMatch {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = headers.get(IF_MATCH)?.to_str().unwrap_or_default();
+
+        if header.trim() == "*" {
+            return Some(Self::Any);
+        }
+
+        Some(Self::Versions(
+            header.split(',').filter_map(tag_version).collect(),
+        ))
+    }
+
+    // What the conditional UPDATE and DELETE compare updated_date against, None skips the check
+    pub fn versions(self) -> Option<Vec<DateTime<Utc>>> {
+        match self {
+            Self::Any => None,
+            Self::Versions(versions) => Some(versions),
+        }
+    }
+}
+
+pub fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|val| val.to_str().ok())
+        .is_some_and(|val| {
+            val.split(',')
+                .any(|tag| [etag, "*"].contains(&tag.trim().trim_start_matches("W/")))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn etag_test() {
+        let date = "2026-10-19T02:46:49.467642+00:00";
+        let etag = row_etag(Some(date), &json!({ "tags": ["mecha"] }));
+        assert!(etag.starts_with("\"1792378009467642-"));
+        assert!(row_etag(None, &json!({})).starts_with("\"0-"));
+
+        // Same version, different body
+        let tagged = row_etag(Some(date), &json!({ "tags": ["mecha", "gundam"] }));
+        assert_ne!(etag, tagged);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, IfMatch::from_headers(&headers));
+
+        headers.insert(IF_MATCH, "*".parse().unwrap());
+        assert_eq!(Some(IfMatch::Any), IfMatch::from_headers(&headers));
+
+        headers.insert(IF_MATCH, format!("W/\"1\", {etag}").parse().unwrap());
+        let versions = IfMatch::from_headers(&headers).unwrap().versions().unwrap();
+        assert_eq!(
+            vec!["2026-10-19T02:46:49.467642+00:00".to_string()],
+            versions
+                .iter()
+                .map(|version| version.to_rfc3339())
+                .collect::<Vec<_>>()
+        );
+
+        headers.insert(IF_NONE_MATCH, format!("W/{etag}").parse().unwrap());
+        assert!(not_modified(&headers, &etag));
+        assert!(!not_modified(&headers, "\"1\""));
+    }
+}
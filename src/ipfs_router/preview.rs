@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+
+use eyre::{eyre, Result};
+use serde::Serialize;
+
+use crate::AppState;
+
+use super::{
+    content_rating::{IngestPolicy, RatingAction},
+    crawl::{existing_hash_ids, normalize_items, validate_item, CrawlOptions},
+    image_source::source_for,
+    CreatePayload, IngestPayload,
+};
+
+// A preview runs inline with the request, so it never walks further than this
+const PREVIEW_MAX_PAGES: u16 = 5;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewStatus {
+    New,
+    Duplicate,
+    Invalid,
+    // The ingest policy would drop the item for its rating
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PreviewItem {
+    page: u16,
+    status: PreviewStatus,
+    reason: Option<String>,
+    action: Option<RatingAction>,
+    row: CreatePayload,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct IngestPreview {
+    source: &'static str,
+    first_page: u16,
+    last_page: u16,
+    total_new: u32,
+    total_duplicate: u32,
+    total_invalid: u32,
+    total_skipped: u32,
+    items: Vec<PreviewItem>,
+}
+
+// Runs the fetch and classification of an ingest without writing anything
+pub async fn preview(state: &AppState, payload: &IngestPayload) -> Result<IngestPreview> {
+    let source = source_for(state, payload).map_err(|err| eyre!(err))?;
+    let search = source.search(payload)?;
+    let options = CrawlOptions::from(payload);
+
+    let first_page = options.start_page;
+    let end_page = options
+        .end_page
+        .unwrap_or(u16::MAX)
+        .min(first_page.saturating_add(PREVIEW_MAX_PAGES - 1));
+
+    let mut report = IngestPreview {
+        source: source.name(),
+        first_page,
+        ..Default::default()
+    };
+
+    for page in first_page..=end_page {
+        let items = source.page(&search, page).await?;
+
+        if items.is_empty() {
+            break;
+        }
+
+        let payloads = normalize_items(source.as_ref(), &items, payload);
+        let mut seen = existing_hash_ids(&state.pool, source.name(), &payloads).await?;
+
+        for row in payloads {
+            let item = classify(page, row, &mut seen, &state.ingest_policy);
+
+            match item.status {
+                PreviewStatus::New => report.total_new += 1,
+                PreviewStatus::Duplicate => report.total_duplicate += 1,
+                PreviewStatus::Invalid => report.total_invalid += 1,
+                PreviewStatus::Skipped => report.total_skipped += 1,
+            }
+
+            report.items.push(item);
+        }
+
+        report.last_page = page;
+    }
+
+    Ok(report)
+}
+
+// The same checks ingest_items runs, in the same order
+fn classify(
+    page: u16,
+    mut row: CreatePayload,
+    seen: &mut HashSet<String>,
+    policy: &IngestPolicy,
+) -> PreviewItem {
+    let (status, reason, action) = match validate_item(&row) {
+        Err(message) => (PreviewStatus::Invalid, Some(message), None),
+        Ok(_) if !seen.insert(row.hash_id.clone()) => (PreviewStatus::Duplicate, None, None),
+        Ok(_) => match policy.action_for(row.content_rating) {
+            RatingAction::Skip => (
+                PreviewStatus::Skipped,
+                Some(format!("{} rating is skipped", row.content_rating.as_str())),
+                Some(RatingAction::Skip),
+            ),
+            action => {
+                row.quarantined = action == RatingAction::Quarantine;
+                (PreviewStatus::New, None, Some(action))
+            }
+        },
+    };
+
+    PreviewItem {
+        page,
+        status,
+        reason,
+        action,
+        row,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::ipfs_router::seaart_resp::item_to_payload;
+
+    fn row(id: &str, url: &str, nsfw: i64) -> CreatePayload {
+        item_to_payload(
+            &json!({ "id": id, "banner": { "url": url, "nsfw": nsfw } }),
+            None,
+        )
+    }
+
+    #[test]
+    fn classify_test() {
+        let policy = IngestPolicy::default();
+        let mut seen = HashSet::from(["stored".to_string()]);
+        let url = "https://cdn.example.com/a.png";
+
+        let new = classify(1, row("a", url, 1), &mut seen, &policy);
+        assert_eq!(PreviewStatus::New, new.status);
+        assert_eq!(Some(RatingAction::Allow), new.action);
+        assert!(!new.row.quarantined);
+
+        // Already stored, or seen earlier in the same preview
+        let stored = classify(1, row("stored", url, 1), &mut seen, &policy);
+        assert_eq!(PreviewStatus::Duplicate, stored.status);
+        let repeated = classify(2, row("a", url, 1), &mut seen, &policy);
+        assert_eq!(PreviewStatus::Duplicate, repeated.status);
+
+        let invalid = classify(
+            1,
+            row("b", "ftp://cdn.example.com/b.png", 1),
+            &mut seen,
+            &policy,
+        );
+        assert_eq!(PreviewStatus::Invalid, invalid.status);
+        assert!(invalid.reason.unwrap().contains("invalid image url"));
+        let missing = classify(1, row("", url, 1), &mut seen, &policy);
+        assert_eq!(Some("missing hash_id".to_string()), missing.reason);
+
+        let sensitive = classify(1, row("c", url, 2), &mut seen, &policy);
+        assert_eq!(PreviewStatus::New, sensitive.status);
+        assert_eq!(Some(RatingAction::Quarantine), sensitive.action);
+        assert!(sensitive.row.quarantined);
+
+        let explicit = classify(1, row("d", url, 3), &mut seen, &policy);
+        assert_eq!(PreviewStatus::Skipped, explicit.status);
+        assert_eq!(
+            Some("explicit rating is skipped".to_string()),
+            explicit.reason
+        );
+    }
+}
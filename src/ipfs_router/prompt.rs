@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+
+// `(term)` multiplies the attention by this, `[term]` divides by it
+const ATTENTION_STEP: f64 = 1.1;
+const NETWORK_KINDS: [&str; 3] = ["lora", "lyco", "hypernet"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PromptTerm {
+    pub text: String,
+    pub weight: f64,
+}
+
+// A LoRA/LyCORIS/hypernetwork `<kind:name:weight>` or an `embedding:name` reference
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkRef {
+    pub kind: String,
+    pub name: String,
+    pub weight: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ParsedPrompt {
+    pub terms: Vec<PromptTerm>,
+    pub networks: Vec<NetworkRef>,
+    pub negative: Option<String>,
+    pub negative_terms: Vec<PromptTerm>,
+}
+
+pub fn parse_prompt(prompt: &str) -> ParsedPrompt {
+    let (positive, negative) = split_negative(prompt);
+    let mut parsed = ParsedPrompt::default();
+
+    parsed.terms = parse_terms(positive, &mut parsed.networks);
+
+    if let Some(negative) = negative.map(str::trim).filter(|n| !n.is_empty()) {
+        parsed.negative_terms = parse_terms(negative, &mut parsed.networks);
+        parsed.negative = Some(negative.to_string());
+    }
+
+    parsed
+}
+
+// A1111 style prompts put the negative prompt and the generation settings on their own lines
+pub fn split_negative(prompt: &str) -> (&str, Option<&str>) {
+    // ASCII lowercasing keeps the byte offsets of the original text
+    let lower = prompt.to_ascii_lowercase();
+
+    let settings = lower.find("\nsteps:").unwrap_or(prompt.len());
+    let prompt = &prompt[..settings];
+
+    match lower[..settings].find("negative prompt:") {
+        Some(start) => (
+            &prompt[..start],
+            Some(&prompt[start + "negative prompt:".len()..]),
+        ),
+        None => (prompt, None),
+    }
+}
+
+fn parse_terms(text: &str, networks: &mut Vec<NetworkRef>) -> Vec<PromptTerm> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    weighted_chunks(&chars, 1.0, &mut chunks, networks);
+
+    let mut terms = Vec::new();
+
+    for (chunk, weight) in chunks {
+        for term in chunk.split([',', '，', '\n']) {
+            let term = term.split_whitespace().collect::<Vec<_>>().join(" ");
+
+            if term.is_empty() || term == "BREAK" {
+                continue;
+            }
+
+            match term.strip_prefix("embedding:") {
+                Some(name) => networks.push(NetworkRef {
+                    kind: "embedding".to_string(),
+                    name: name.to_string(),
+                    weight: round_weight(weight),
+                }),
+                None => terms.push(PromptTerm {
+                    text: term,
+                    weight: round_weight(weight),
+                }),
+            }
+        }
+    }
+
+    terms
+}
+
+// Splits the text into runs that share the same attention weight
+fn weighted_chunks(
+    chars: &[char],
+    weight: f64,
+    chunks: &mut Vec<(String, f64)>,
+    networks: &mut Vec<NetworkRef>,
+) {
+    let mut buf = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '\\' && i + 1 < chars.len() {
+            buf.push(chars[i + 1]);
+            i += 2;
+            continue;
+        }
+
+        let close = match c {
+            '(' | '[' => find_close(chars, i),
+            '<' => chars[i..].iter().position(|&c| c == '>').map(|pos| i + pos),
+            _ => None,
+        };
+
+        let Some(close) = close else {
+            buf.push(c);
+            i += 1;
+            continue;
+        };
+
+        let inner = &chars[i + 1..close];
+
+        match c {
+            '(' => {
+                flush(&mut buf, weight, chunks);
+                match split_weight(inner) {
+                    Some((inner, factor)) => {
+                        weighted_chunks(inner, weight * factor, chunks, networks)
+                    }
+                    None => weighted_chunks(inner, weight * ATTENTION_STEP, chunks, networks),
+                }
+            }
+            '[' => {
+                flush(&mut buf, weight, chunks);
+                weighted_chunks(inner, weight / ATTENTION_STEP, chunks, networks);
+            }
+            _ => match parse_network(&inner.iter().collect::<String>()) {
+                Some(network) => {
+                    flush(&mut buf, weight, chunks);
+                    networks.push(network);
+                }
+                None => buf.extend(&chars[i..=close]),
+            },
+        }
+
+        i = close + 1;
+    }
+
+    flush(&mut buf, weight, chunks);
+}
+
+fn flush(buf: &mut String, weight: f64, chunks: &mut Vec<(String, f64)>) {
+    if !buf.trim().is_empty() {
+        chunks.push((buf.clone(), weight));
+    }
+    buf.clear();
+}
+
+// Index of the bracket closing the one at `open`, unbalanced brackets are plain text
+fn find_close(chars: &[char], open: usize) -> Option<usize> {
+    let mut stack = Vec::new();
+    let mut i = open;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '(' => stack.push(')'),
+            '[' => stack.push(']'),
+            c @ (')' | ']') => {
+                if stack.pop() != Some(c) {
+                    return None;
+                }
+                if stack.is_empty() {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// `(text:1.2)` sets the weight explicitly, the colon has to be outside nested brackets
+fn split_weight(inner: &[char]) -> Option<(&[char], f64)> {
+    let mut depth = 0;
+    let mut colon = None;
+
+    for (i, c) in inner.iter().enumerate() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ':' if depth == 0 => colon = Some(i),
+            _ => {}
+        }
+    }
+
+    let colon = colon?;
+    let weight = inner[colon + 1..]
+        .iter()
+        .collect::<String>()
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+
+    Some((&inner[..colon], weight))
+}
+
+fn parse_network(inner: &str) -> Option<NetworkRef> {
+    let mut parts = inner.split(':').map(str::trim);
+    let kind = parts.next()?.to_lowercase();
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let weight = match parts.next() {
+        Some(weight) => weight.parse().ok()?,
+        None => 1.0,
+    };
+
+    NETWORK_KINDS.contains(&kind.as_str()).then(|| NetworkRef {
+        kind,
+        name: name.to_string(),
+        weight,
+    })
+}
+
+fn round_weight(weight: f64) -> f64 {
+    (weight * 1000.0).round() / 1000.0
+}
+
+// Rows stored before prompts were parsed get their parsed form on startup
+pub async fn backfill_parsed_prompts(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, prompt AS "prompt!"
+        FROM ipfs_image
+        WHERE parsed_prompt IS NULL AND prompt IS NOT NULL
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for row in &rows {
+        sqlx::query!(
+            r#"
+            UPDATE ipfs_image
+            SET parsed_prompt = $2
+            WHERE id = $1
+            "#,
+            row.id,
+            json!(parse_prompt(&row.prompt)),
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(rows.len() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn term(text: &str, weight: f64) -> PromptTerm {
+        PromptTerm {
+            text: text.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        let parsed = parse_prompt(
+            "masterpiece, (best quality:1.2), ((red hair)), [blurry]，Verism \\(style\\), \
+             (gundam, <lora:mecha_v2:0.8> mecha:1.3), embedding:easynegative\n\
+             Negative prompt: lowres, (bad hands:1.4)\n\
+             Steps: 20, Sampler: Euler a",
+        );
+
+        assert_eq!(
+            vec![
+                term("masterpiece", 1.0),
+                term("best quality", 1.2),
+                term("red hair", 1.21),
+                term("blurry", 0.909),
+                term("Verism (style)", 1.0),
+                term("gundam", 1.3),
+                term("mecha", 1.3),
+            ],
+            parsed.terms
+        );
+        assert_eq!(
+            vec!["mecha_v2", "easynegative"],
+            parsed
+                .networks
+                .iter()
+                .map(|n| n.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0.8, parsed.networks[0].weight);
+        assert_eq!(Some("lowres, (bad hands:1.4)".to_string()), parsed.negative);
+        assert_eq!(
+            vec![term("lowres", 1.0), term("bad hands", 1.4)],
+            parsed.negative_terms
+        );
+    }
+
+    #[test]
+    fn parse_unbalanced_test() {
+        let parsed = parse_prompt("smile :), (cat, <not a network>");
+
+        assert_eq!(
+            vec![
+                term("smile :)", 1.0),
+                term("(cat", 1.0),
+                term("<not a network>", 1.0)
+            ],
+            parsed.terms
+        );
+        assert!(parsed.negative.is_none());
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    hash::{Hash, Hasher},
+    io::Cursor,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use eyre::{eyre, Result};
+
+use super::image_analysis::decode_image;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    ColorType, DynamicImage, ImageEncoder,
+};
+
+// Where rendered files and downloaded originals are kept, the largest side that can be asked for
+// and the sides whose renders are written to the cache
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub cache_dir: PathBuf,
+    pub max_size: u32,
+    pub cache_sizes: Vec<u32>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            cache_dir: PathBuf::from("render_cache"),
+            max_size: 4096,
+            cache_sizes: vec![64, 128, 256, 320, 512, 640, 1024, 1280, 1920, 2048],
+        }
+    }
+}
+
+impl RenderConfig {
+    // RENDER_CACHE_DIR and RENDER_MAX_SIZE override the defaults, RENDER_CACHE_SIZES is
+    // comma separated
+    pub fn from_env() -> Self {
+        let mut config = RenderConfig::default();
+
+        if let Ok(dir) = env::var("RENDER_CACHE_DIR") {
+            config.cache_dir = PathBuf::from(dir);
+        }
+
+        if let Ok(max_size) = env::var("RENDER_MAX_SIZE") {
+            config.max_size = max_size.parse().expect("RENDER_MAX_SIZE is invalid");
+        }
+
+        if let Ok(sizes) = env::var("RENDER_CACHE_SIZES") {
+            config.cache_sizes = sizes
+                .split(',')
+                .map(|size| size.trim().parse().expect("RENDER_CACHE_SIZES is invalid"))
+                .collect();
+        }
+
+        config
+    }
+
+    // Only renders whose sides are all listed are kept, which bounds the files per image to
+    // the combinations of those sides, fits and formats; anything else is rendered per request
+    pub fn cacheable(&self, params: &RenderParams) -> bool {
+        [params.width, params.height]
+            .into_iter()
+            .flatten()
+            .all(|side| self.cache_sizes.contains(&side))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    // Inside the box, aspect ratio kept
+    #[default]
+    Contain,
+    // Fills the box, the overflow is cropped from the center
+    Cover,
+    // Stretched to the box
+    Fill,
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "contain" => Ok(Self::Contain),
+            "cover" => Ok(Self::Cover),
+            "fill" => Ok(Self::Fill),
+            other => Err(format!("unknown fit `{other}`")),
+        }
+    }
+}
+
+impl Fit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Contain => "contain",
+            Self::Cover => "cover",
+            Self::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl FromStr for RenderFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "webp" => Ok(Self::Webp),
+            other => Err(format!("unknown format `{other}`")),
+        }
+    }
+}
+
+impl RenderFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RenderParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: RenderFormat,
+}
+
+impl RenderParams {
+    // `w`, `h`, `fit` and `format` of the query string, sides are 1..=max_size
+    pub fn from_query(params: &HashMap<String, String>, max_size: u32) -> Result<Self, String> {
+        let side = |key: &str| -> Result<Option<u32>, String> {
+            match params.get(key) {
+                Some(val) => match val.parse::<u32>() {
+                    Ok(side) if (1..=max_size).contains(&side) => Ok(Some(side)),
+                    _ => Err(format!(
+                        "{key} must be between 1 and {max_size}, got `{val}`"
+                    )),
+                },
+                None => Ok(None),
+            }
+        };
+
+        Ok(RenderParams {
+            width: side("w")?,
+            height: side("h")?,
+            fit: params
+                .get("fit")
+                .map_or(Ok(Fit::default()), |fit| fit.parse())?,
+            format: params
+                .get("format")
+                .map_or(Ok(RenderFormat::default()), |format| format.parse())?,
+        })
+    }
+
+    // The query string that asks for these parameters
+    pub fn query(&self) -> String {
+        let mut query = Vec::new();
+        if let Some(width) = self.width {
+            query.push(format!("w={width}"));
+        }
+        if let Some(height) = self.height {
+            query.push(format!("h={height}"));
+        }
+        query.push(format!("fit={}", self.fit.as_str()));
+        query.push(format!("format={}", self.format.extension()));
+
+        query.join("&")
+    }
+
+    // Unique per set of parameters, used as the cache file name
+    pub fn file_name(&self) -> String {
+        let side = |side: Option<u32>| side.map_or("auto".to_string(), |side| side.to_string());
+
+        format!(
+            "{}x{}-{}.{}",
+            side(self.width),
+            side(self.height),
+            self.fit.as_str(),
+            self.format.extension()
+        )
+    }
+}
+
+pub fn render(bytes: &[u8], params: &RenderParams) -> Result<Vec<u8>> {
+    let img = decode_image(bytes)?;
+
+    let img = match (params.width, params.height) {
+        (Some(width), Some(height)) => match params.fit {
+            Fit::Contain => img.resize(width, height, FilterType::CatmullRom),
+            Fit::Cover => img.resize_to_fill(width, height, FilterType::CatmullRom),
+            Fit::Fill => img.resize_exact(width, height, FilterType::CatmullRom),
+        },
+        (Some(width), None) => img.resize(width, u32::MAX, FilterType::CatmullRom),
+        (None, Some(height)) => img.resize(u32::MAX, height, FilterType::CatmullRom),
+        (None, None) => img,
+    };
+
+    encode(&img, params.format)
+}
+
+fn encode(img: &DynamicImage, format: RenderFormat) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (width, height) = (img.width(), img.height());
+
+    match format {
+        // JPEG has no alpha channel
+        RenderFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, 85).write_image(
+            img.to_rgb8().as_raw(),
+            width,
+            height,
+            ColorType::Rgb8,
+        )?,
+        RenderFormat::Png => PngEncoder::new(&mut bytes).write_image(
+            img.to_rgba8().as_raw(),
+            width,
+            height,
+            ColorType::Rgba8,
+        )?,
+        RenderFormat::Webp => WebPEncoder::new_lossless(Cursor::new(&mut bytes)).encode(
+            img.to_rgba8().as_raw(),
+            width,
+            height,
+            ColorType::Rgba8,
+        )?,
+    }
+
+    Ok(bytes)
+}
+
+pub fn digest(value: impl Hash) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+// Renders of a row change only with the URL of its source, the `v` a render URL is pinned to
+pub fn render_version(source_url: &str) -> String {
+    digest(source_url)
+}
+
+// Renders live under the URLs they come from, so changing the image of a row starts a fresh cache
+pub fn cache_dir(config: &RenderConfig, id: i32, source_url: &str) -> PathBuf {
+    config
+        .cache_dir
+        .join(id.to_string())
+        .join(render_version(source_url))
+}
+
+// Known from the source and the parameters alone, so a revalidation never renders
+pub fn render_etag(version: &str, params: &RenderParams) -> String {
+    format!("\"{}\"", digest((version, params.file_name())))
+}
+
+// Writes through a temporary file so concurrent requests never read half a render
+pub async fn write_cached(path: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| eyre!("cache path has no parent"))?;
+    tokio::fs::create_dir_all(dir).await?;
+
+    let tmp = path.with_extension(format!("tmp{}", fastrand::u32(..)));
+    tokio::fs::write(&tmp, bytes).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn render_test() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(300, 200))
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let cases = [
+            (vec![("w", "150")], (150, 100)),
+            (vec![("h", "50"), ("format", "png")], (75, 50)),
+            (vec![("w", "100"), ("h", "100")], (100, 67)),
+            (
+                vec![("w", "100"), ("h", "100"), ("fit", "cover")],
+                (100, 100),
+            ),
+            (vec![("w", "100"), ("h", "10"), ("fit", "fill")], (100, 10)),
+            (vec![("format", "webp")], (300, 200)),
+        ];
+
+        for (pairs, size) in cases {
+            let params = RenderParams::from_query(&query(&pairs), 4096).unwrap();
+            let rendered = image::load_from_memory(&render(&png, &params).unwrap()).unwrap();
+            assert_eq!(size, (rendered.width(), rendered.height()), "{pairs:?}");
+        }
+
+        let params = RenderParams::from_query(&query(&[("w", "64"), ("fit", "cover")]), 4096);
+        let params = params.unwrap();
+        assert_eq!("64xauto-cover.jpg", params.file_name());
+        assert_eq!("w=64&fit=cover&format=jpg", params.query());
+        assert_eq!(
+            params,
+            RenderParams::from_query(
+                &query(&[("w", "64"), ("fit", "cover"), ("format", "jpg")]),
+                4096
+            )
+            .unwrap()
+        );
+
+        // The ETag moves with the source and the parameters
+        let version = render_version("https://cdn.example.com/a.png");
+        assert_eq!(version, render_version("https://cdn.example.com/a.png"));
+        assert_ne!(version, render_version("https://cdn.example.com/b.png"));
+        assert_ne!(
+            render_etag(&version, &params),
+            render_etag(&version, &RenderParams::default())
+        );
+
+        assert!(RenderParams::from_query(&query(&[("w", "0")]), 4096).is_err());
+        assert!(RenderParams::from_query(&query(&[("h", "5000")]), 4096).is_err());
+        assert!(RenderParams::from_query(&query(&[("format", "gif")]), 4096).is_err());
+
+        let config = RenderConfig::default();
+        let cacheable = |pairs: &[(&str, &str)]| {
+            config.cacheable(&RenderParams::from_query(&query(pairs), 4096).unwrap())
+        };
+        assert!(cacheable(&[]));
+        assert!(cacheable(&[("w", "320"), ("h", "320"), ("fit", "cover")]));
+        assert!(!cacheable(&[("w", "321")]));
+        assert!(!cacheable(&[("w", "320"), ("h", "333")]));
+    }
+}
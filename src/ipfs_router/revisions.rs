@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+
+use axum::http::HeaderMap;
+use serde::Serialize;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    Pool, Postgres, Transaction,
+};
+
+use super::{categories::ensure_category, ipfs_model::datetime_to_string};
+
+// Who made a change, sent by the editor front end. Changes without it are still recorded.
+pub const ACTOR_HEADER: &str = "x-actor";
+const UNKNOWN_ACTOR: &str = "anonymous";
+
+pub fn actor(headers: &HeaderMap) -> String {
+    headers
+        .get(ACTOR_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .map(str::trim)
+        .filter(|actor| !actor.is_empty())
+        .unwrap_or(UNKNOWN_ACTOR)
+        .to_string()
+}
+
+// The columns of an image row that revisions track
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedValues {
+    pub image: String,
+    pub ipfs_image_url: String,
+    pub category: Option<String>,
+}
+
+#[derive(Debug)]
+struct RevisionRow {
+    id: i32,
+    image_id: i32,
+    old_image: String,
+    new_image: String,
+    old_ipfs_image_url: String,
+    new_ipfs_image_url: String,
+    old_category: Option<String>,
+    new_category: Option<String>,
+    actor: String,
+    reverted_revision_id: Option<i32>,
+    time_created: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    old: Option<String>,
+    new: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Revision {
+    id: i32,
+    image_id: i32,
+    actor: String,
+    reverted_revision_id: Option<i32>,
+    created: Option<String>,
+    // Only the columns this revision changed
+    changes: BTreeMap<&'static str, Change>,
+}
+
+impl RevisionRow {
+    fn old_values(&self) -> TrackedValues {
+        TrackedValues {
+            image: self.old_image.clone(),
+            ipfs_image_url: self.old_ipfs_image_url.clone(),
+            category: self.old_category.clone(),
+        }
+    }
+
+    fn new_values(&self) -> TrackedValues {
+        TrackedValues {
+            image: self.new_image.clone(),
+            ipfs_image_url: self.new_ipfs_image_url.clone(),
+            category: self.new_category.clone(),
+        }
+    }
+}
+
+fn changes(old: &TrackedValues, new: &TrackedValues) -> BTreeMap<&'static str, Change> {
+    let fields = [
+        ("image", Some(&old.image), Some(&new.image)),
+        (
+            "ipfs_image_url",
+            Some(&old.ipfs_image_url),
+            Some(&new.ipfs_image_url),
+        ),
+        ("category", old.category.as_ref(), new.category.as_ref()),
+    ];
+
+    fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| {
+            (
+                field,
+                Change {
+                    old: old.cloned(),
+                    new: new.cloned(),
+                },
+            )
+        })
+        .collect()
+}
+
+// Undoing a revision puts back the old value of each column it changed and leaves the others,
+// so later edits to other columns survive
+fn undo(revision: &RevisionRow, current: &TrackedValues) -> TrackedValues {
+    fn pick<T: PartialEq>(old: T, new: T, current: T) -> T {
+        if old != new {
+            old
+        } else {
+            current
+        }
+    }
+
+    let (old, new) = (revision.old_values(), revision.new_values());
+
+    TrackedValues {
+        image: pick(old.image, new.image, current.image.clone()),
+        ipfs_image_url: pick(
+            old.ipfs_image_url,
+            new.ipfs_image_url,
+            current.ipfs_image_url.clone(),
+        ),
+        category: pick(old.category, new.category, current.category.clone()),
+    }
+}
+
+pub async fn lock_tracked_values(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+) -> Result<TrackedValues, sqlx::Error> {
+    sqlx::query_as!(
+        TrackedValues,
+        r#"
+        SELECT image, ipfs_image_url, category
+        FROM ipfs_image
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        id,
+    )
+    .fetch_one(tx)
+    .await
+}
+
+// Nothing is written when the update left the tracked columns as they were
+pub async fn record_revision(
+    tx: &mut Transaction<'_, Postgres>,
+    image_id: i32,
+    old: &TrackedValues,
+    new: &TrackedValues,
+    actor: &str,
+    reverted_revision_id: Option<i32>,
+) -> Result<(), sqlx::Error> {
+    if old == new {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO ipfs_image_revisions (
+            image_id, old_image, new_image, old_ipfs_image_url, new_ipfs_image_url,
+            old_category, new_category, actor, reverted_revision_id
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        image_id,
+        old.image,
+        new.image,
+        old.ipfs_image_url,
+        new.ipfs_image_url,
+        old.category.as_deref(),
+        new.category.as_deref(),
+        actor,
+        reverted_revision_id,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+// Newest first
+pub async fn list_revisions(
+    pool: &Pool<Postgres>,
+    image_id: i32,
+) -> Result<Vec<Revision>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        RevisionRow,
+        r#"
+        SELECT
+            id, image_id, old_image, new_image, old_ipfs_image_url, new_ipfs_image_url,
+            old_category, new_category, actor, reverted_revision_id, time_created
+        FROM ipfs_image_revisions
+        WHERE image_id = $1
+        ORDER BY id DESC
+        "#,
+        image_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let revisions = rows
+        .into_iter()
+        .map(|row| Revision {
+            changes: changes(&row.old_values(), &row.new_values()),
+            id: row.id,
+            image_id: row.image_id,
+            actor: row.actor,
+            reverted_revision_id: row.reverted_revision_id,
+            created: datetime_to_string(row.time_created),
+        })
+        .collect();
+
+    Ok(revisions)
+}
+
+// RowNotFound when the revision does not belong to the image
+pub async fn revert_revision(
+    pool: &Pool<Postgres>,
+    image_id: i32,
+    revision_id: i32,
+    actor: &str,
+    versions: Option<&[DateTime<Utc>]>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let revision = sqlx::query_as!(
+        RevisionRow,
+        r#"
+        SELECT
+            id, image_id, old_image, new_image, old_ipfs_image_url, new_ipfs_image_url,
+            old_category, new_category, actor, reverted_revision_id, time_created
+        FROM ipfs_image_revisions
+        WHERE id = $1 AND image_id = $2
+        "#,
+        revision_id,
+        image_id,
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let current = lock_tracked_values(&mut tx, image_id).await?;
+    let mut target = undo(&revision, &current);
+    // The category may have been deleted since
+    target.category = ensure_category(&mut tx, target.category.as_deref()).await?;
+
+    // Guarded like update_data, nothing is written when If-Match names an older version
+    let res = sqlx::query!(
+        r#"
+        UPDATE ipfs_image
+        SET image = $2, ipfs_image_url = $3, category = $4, updated_date = NOW()
+        WHERE id = $1 AND ($5::TIMESTAMPTZ[] IS NULL OR updated_date = ANY($5))
+        "#,
+        image_id,
+        target.image,
+        target.ipfs_image_url,
+        target.category.as_deref(),
+        versions,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    record_revision(
+        &mut tx,
+        image_id,
+        &current,
+        &target,
+        actor,
+        Some(revision_id),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(image: &str, url: &str, category: Option<&str>) -> TrackedValues {
+        TrackedValues {
+            image: image.to_string(),
+            ipfs_image_url: url.to_string(),
+            category: category.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn revision_test() {
+        let old = values("a.png", "NO_IPFS", None);
+        let new = values("a.png", "ipfs://cid", Some("anime"));
+
+        let changed = changes(&old, &new);
+        assert_eq!(
+            vec!["category", "ipfs_image_url"],
+            changed.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            &Change {
+                old: None,
+                new: Some("anime".to_string())
+            },
+            &changed["category"]
+        );
+
+        let revision = RevisionRow {
+            id: 1,
+            image_id: 8,
+            old_image: old.image.clone(),
+            new_image: new.image.clone(),
+            old_ipfs_image_url: old.ipfs_image_url.clone(),
+            new_ipfs_image_url: new.ipfs_image_url.clone(),
+            old_category: old.category.clone(),
+            new_category: new.category.clone(),
+            actor: "editor".to_string(),
+            reverted_revision_id: None,
+            time_created: None,
+        };
+
+        // The image was changed later, undoing revision 1 keeps that
+        let current = values("b.png", "ipfs://cid", Some("anime"));
+        assert_eq!(values("b.png", "NO_IPFS", None), undo(&revision, &current));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!("anonymous", actor(&headers));
+        headers.insert(ACTOR_HEADER, " alice ".parse().unwrap());
+        assert_eq!("alice", actor(&headers));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{str::FromStr, time::Duration};
+
+use cron::Schedule;
+use serde::Serialize;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    FromRow, Pool, Postgres,
+};
+
+use crate::AppState;
+
+use super::{ipfs_model::datetime_to_string, jobs::start_job, IngestPayload, SavedSearchPayload};
+
+const SCHEDULER_TICK: Duration = Duration::from_secs(30);
+
+#[derive(FromRow, Debug)]
+struct SavedSearchRow {
+    id: i32,
+    keyword: String,
+    tags: Vec<String>,
+    category: Option<String>,
+    order_by: String,
+    schedule: String,
+    enabled: bool,
+    last_run_at: Option<DateTime<Utc>>,
+    time_created: Option<DateTime<Utc>>,
+    updated_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SavedSearch {
+    id: i32,
+    keyword: String,
+    tags: Vec<String>,
+    category: Option<String>,
+    order_by: String,
+    schedule: String,
+    enabled: bool,
+    last_run_at: Option<String>,
+    next_run_at: Option<String>,
+    created: Option<String>,
+    updated_date: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SavedSearchRun {
+    id: i32,
+    job_id: Option<i32>,
+    status: String,
+    message: Option<String>,
+    created: Option<String>,
+    job_status: Option<String>,
+    total_inserted: Option<i32>,
+    total_duplicate: Option<i32>,
+    total_failure: Option<i32>,
+    finished_at: Option<String>,
+}
+
+// Accepts the classic five field crontab format as well, the cron crate wants seconds first
+pub fn parse_schedule(schedule: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = schedule.split_whitespace().collect();
+
+    let expr = match fields.as_slice() {
+        [minute, hour, day, month, weekday] => {
+            let weekday = crontab_weekdays(weekday)
+                .ok_or_else(|| format!("invalid schedule `{schedule}`: bad day of week"))?;
+            format!("0 {minute} {hour} {day} {month} {weekday}")
+        }
+        _ => schedule.to_string(),
+    };
+
+    Schedule::from_str(&expr).map_err(|err| format!("invalid schedule `{schedule}`: {err}"))
+}
+
+// Crontab numbers the days of week 0-7 with Sunday as 0 and 7, the cron crate 1-7 with Sunday as 1.
+// Names and `*` mean the same to both and are kept.
+fn crontab_weekdays(field: &str) -> Option<String> {
+    let mut parts = Vec::new();
+
+    for part in field.split(',') {
+        if part == "*" || part == "?" || part.chars().any(|c| c.is_ascii_alphabetic()) {
+            parts.push(part.to_string());
+            continue;
+        }
+
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (start.parse::<u8>().ok()?, end.parse::<u8>().ok()?),
+            None if step > 1 => (range.parse().ok()?, 7),
+            None => (range.parse().ok()?, range.parse().ok()?),
+        };
+
+        if start > end || end > 7 {
+            return None;
+        }
+
+        let days = (start..=end)
+            .step_by(step)
+            .map(|day| (day % 7 + 1).to_string());
+        parts.extend(days);
+    }
+
+    Some(parts.join(","))
+}
+
+fn next_run(schedule: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    parse_schedule(schedule).ok()?.after(&after).next()
+}
+
+impl From<SavedSearchRow> for SavedSearch {
+    fn from(row: SavedSearchRow) -> Self {
+        let since = row
+            .last_run_at
+            .or(row.time_created)
+            .unwrap_or_else(Utc::now);
+
+        SavedSearch {
+            id: row.id,
+            next_run_at: datetime_to_string(next_run(&row.schedule, since)),
+            keyword: row.keyword,
+            tags: row.tags,
+            category: row.category,
+            order_by: row.order_by,
+            schedule: row.schedule,
+            enabled: row.enabled,
+            last_run_at: datetime_to_string(row.last_run_at),
+            created: datetime_to_string(row.time_created),
+            updated_date: datetime_to_string(row.updated_date),
+        }
+    }
+}
+
+pub async fn create_saved_search(
+    pool: &Pool<Postgres>,
+    payload: &SavedSearchPayload,
+) -> Result<SavedSearch, sqlx::Error> {
+    let row = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        INSERT INTO saved_searches (keyword, tags, category, order_by, schedule, enabled)
+        VALUES ($1, $2, $3, COALESCE($4, 'hot'), $5, COALESCE($6, TRUE))
+        RETURNING *
+        "#,
+        payload.keyword,
+        &payload.tags,
+        payload.category.as_deref(),
+        payload.order_by.as_deref(),
+        payload.schedule,
+        payload.enabled,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(SavedSearch::from(row))
+}
+
+pub async fn list_saved_searches(pool: &Pool<Postgres>) -> Result<Vec<SavedSearch>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        SELECT *
+        FROM saved_searches
+        ORDER BY id
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(SavedSearch::from).collect())
+}
+
+pub async fn delete_saved_search(pool: &Pool<Postgres>, id: i32) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM saved_searches
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+pub async fn list_runs(
+    pool: &Pool<Postgres>,
+    saved_search_id: i32,
+) -> Result<Vec<SavedSearchRun>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            r.id, r.job_id, r.status, r.message, r.time_created,
+            j.status AS "job_status?", j.total_inserted AS "total_inserted?",
+            j.total_duplicate AS "total_duplicate?", j.total_failure AS "total_failure?",
+            j.finished_at
+        FROM saved_search_runs r
+        LEFT JOIN ingest_jobs j ON j.id = r.job_id
+        WHERE r.saved_search_id = $1
+        ORDER BY r.id DESC
+        "#,
+        saved_search_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let runs = rows
+        .into_iter()
+        .map(|row| SavedSearchRun {
+            id: row.id,
+            job_id: row.job_id,
+            status: row.status,
+            message: row.message,
+            created: datetime_to_string(row.time_created),
+            job_status: row.job_status,
+            total_inserted: row.total_inserted,
+            total_duplicate: row.total_duplicate,
+            total_failure: row.total_failure,
+            finished_at: datetime_to_string(row.finished_at),
+        })
+        .collect();
+
+    Ok(runs)
+}
+
+pub fn spawn_scheduler(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULER_TICK);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = run_due_searches(&state).await {
+                eprintln!("Saved searches were not run: {err}");
+            }
+        }
+    });
+}
+
+async fn run_due_searches(state: &AppState) -> Result<(), sqlx::Error> {
+    let pool = &state.pool;
+
+    let searches = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        SELECT *
+        FROM saved_searches
+        WHERE enabled
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let now = Utc::now();
+
+    for search in searches {
+        let since = search.last_run_at.or(search.time_created).unwrap_or(now);
+        match next_run(&search.schedule, since) {
+            Some(next) if next <= now => {}
+            _ => continue,
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE saved_searches
+            SET last_run_at = $2
+            WHERE id = $1
+            "#,
+            search.id,
+            now,
+        )
+        .execute(pool)
+        .await?;
+
+        let previous_job = sqlx::query_scalar!(
+            r#"
+            SELECT job_id
+            FROM saved_search_runs
+            WHERE saved_search_id = $1 AND job_id IS NOT NULL
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            search.id,
+        )
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+
+        if previous_job.is_some_and(|job_id| state.jobs.is_running(job_id)) {
+            record_run(
+                pool,
+                search.id,
+                None,
+                "skipped",
+                Some("previous run is still active"),
+            )
+            .await?;
+            continue;
+        }
+
+        let payload = IngestPayload {
+            source: None,
+            q: search.keyword,
+            tags: search.tags,
+            category: search.category,
+            order_by: Some(search.order_by),
+            page: None,
+            crawl: true,
+            end_page: None,
+            max_items: None,
+            delay_ms: None,
+            resume: false,
+            url: None,
+            selectors: None,
+            download: false,
+            near_duplicates: None,
+            near_duplicate_distance: None,
+        };
+
+        match start_job(state, payload).await {
+            Ok(job_id) => record_run(pool, search.id, Some(job_id), "started", None).await?,
+            Err(err) => {
+                let message = err.to_string();
+                record_run(pool, search.id, None, "failed", Some(&message)).await?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn record_run(
+    pool: &Pool<Postgres>,
+    saved_search_id: i32,
+    job_id: Option<i32>,
+    status: &str,
+    message: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO saved_search_runs (saved_search_id, job_id, status, message)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        saved_search_id,
+        job_id,
+        status,
+        message,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schedule_test() {
+        let after = DateTime::parse_from_rfc3339("2023-08-20T10:15:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let daily = next_run("30 6 * * *", after).unwrap();
+        assert_eq!("2023-08-21T06:30:00+00:00", daily.to_rfc3339());
+
+        let with_seconds = next_run("0 0 */2 * * *", after).unwrap();
+        assert_eq!("2023-08-20T12:00:00+00:00", with_seconds.to_rfc3339());
+
+        assert!(parse_schedule("every day").is_err());
+    }
+
+    #[test]
+    fn weekday_schedule_test() {
+        let at = |date: &str| {
+            DateTime::parse_from_rfc3339(date)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+
+        // 2023-08-20 is a Sunday, weekdays start on Monday
+        let weekdays = next_run("0 9 * * 1-5", at("2023-08-20T10:15:00Z")).unwrap();
+        assert_eq!("2023-08-21T09:00:00+00:00", weekdays.to_rfc3339());
+        let weekdays = next_run("0 9 * * 1-5", at("2023-08-25T10:15:00Z")).unwrap();
+        assert_eq!("2023-08-28T09:00:00+00:00", weekdays.to_rfc3339());
+
+        let sunday = next_run("30 8 * * 0", at("2023-08-21T10:15:00Z")).unwrap();
+        assert_eq!("2023-08-27T08:30:00+00:00", sunday.to_rfc3339());
+        assert_eq!(
+            sunday,
+            next_run("30 8 * * 7", at("2023-08-21T10:15:00Z")).unwrap()
+        );
+        assert_eq!(
+            sunday,
+            next_run("30 8 * * SUN", at("2023-08-21T10:15:00Z")).unwrap()
+        );
+
+        let weekend = next_run("0 0 * * 6-7", at("2023-08-26T12:00:00Z")).unwrap();
+        assert_eq!("2023-08-27T00:00:00+00:00", weekend.to_rfc3339());
+
+        assert_eq!(Some("1,3,5,7".to_string()), crontab_weekdays("*/2"));
+        assert_eq!(Some("2,6".to_string()), crontab_weekdays("1,5"));
+        assert!(parse_schedule("0 9 * * 8").is_err());
+        assert!(parse_schedule("0 9 * * 5-1").is_err());
+    }
+}
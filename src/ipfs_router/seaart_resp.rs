@@ -1,10 +1,19 @@
 // use eyre::{eyre, Result};
+use std::{env, sync::Arc, time::Duration};
+
 use reqwest::Client;
 use serde_json::{json, Value};
 
-// use super::CreatePayload;
+use super::{
+    content_rating::rate_item,
+    upstream::{UpstreamError, Upstreams},
+    AuthorPayload, CreatePayload,
+};
 
-const USER_AGENT :&str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36";
+pub const USER_AGENT :&str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36";
+const DEFAULT_BASE_URL: &str = "https://www.seaart.ai";
+const LIST_PATH: &str = "/api/v1/artwork/list";
+const UPSTREAM: &str = "seaart";
 
 // pub async fn get_resp_data(query_search: &str, category: &str) -> Result<Vec<CreatePayload>> {
 //     let keyword = query_search.replace('+', " ");
@@ -59,28 +68,174 @@ pub fn extract_obj(val: &Value) -> (&str, &str, &str, i32, i32) {
     (image, hash_id, prompt, width, height)
 }
 
-pub async fn get_raw_value(
-    query_search: &str,
-    pages: u16,
-    tags: Vec<&String>,
-) -> Result<Value, reqwest::Error> {
-    let keyword = query_search.replace("+", " ");
-    dbg!(&keyword);
-    let url = "https://www.seaart.ai/api/v1/artwork/list";
-
-    let payload = json!({
-        "keyword": keyword,
-        "order_by": "hot",
-        "page": pages,
-        "page_size": 60,
-        "tags": tags,
-        "type": "community"
-    });
-
-    let client = Client::builder().user_agent(USER_AGENT).build()?;
-
-    let resp: Value = client.post(url).json(&payload).send().await?.json().await?;
-    Ok(resp)
+pub fn extract_author(val: &Value) -> Option<AuthorPayload> {
+    let author = &val["author"];
+    let id = author["id"].as_str().filter(|id| !id.is_empty())?;
+    let name = author["name"].as_str().unwrap_or_default();
+    let avatar = author["head"].as_str().filter(|head| !head.is_empty());
+
+    Some(AuthorPayload {
+        id: id.to_string(),
+        name: name.to_string(),
+        avatar: avatar.map(str::to_string),
+    })
+}
+
+pub fn extract_stat(val: &Value) -> (i32, i32, i32) {
+    let stat = &val["stat"];
+    let like = stat["num_of_like"].as_i64().unwrap_or_default() as i32;
+    let collection = stat["num_of_collection"].as_i64().unwrap_or_default() as i32;
+    let view = stat["num_of_view"].as_i64().unwrap_or_default() as i32;
+
+    (like, collection, view)
+}
+
+pub fn item_to_payload(val: &Value, category: Option<&String>) -> CreatePayload {
+    let (image, hash_id, prompt, width, height) = extract_obj(val);
+    let (num_of_like, num_of_collection, num_of_view) = extract_stat(val);
+    let non_empty = |key: &str| {
+        val[key]
+            .as_str()
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+
+    CreatePayload {
+        image: image.to_string(),
+        ipfs_image_url: "NO_IPFS".to_string(),
+        category: category.cloned(),
+        width,
+        height,
+        prompt: Some(prompt.to_string()),
+        hash_id: hash_id.to_string(),
+        author: extract_author(val),
+        model_id: non_empty("model_id"),
+        channel: non_empty("channel"),
+        num_of_like,
+        num_of_collection,
+        num_of_view,
+        content_rating: rate_item(val),
+        quarantined: false,
+        source: Some("seaart".to_string()),
+        tags: Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SeaArtClient {
+    client: Client,
+    base_url: String,
+    order_by: String,
+    page_size: u16,
+    kind: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl SeaArtClient {
+    pub fn new(base_url: impl Into<String>) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(SeaArtClient {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            order_by: "hot".to_string(),
+            page_size: 60,
+            kind: "community".to_string(),
+            upstreams: Arc::new(Upstreams::default()),
+        })
+    }
+
+    // SEAART_BASE_URL, SEAART_ORDER_BY, SEAART_PAGE_SIZE and SEAART_TYPE override the defaults
+    pub fn from_env() -> Result<Self, reqwest::Error> {
+        let base_url = env::var("SEAART_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        let mut client = SeaArtClient::new(base_url)?;
+
+        if let Ok(order_by) = env::var("SEAART_ORDER_BY") {
+            client = client.with_order_by(order_by);
+        }
+
+        if let Some(page_size) = env::var("SEAART_PAGE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+        {
+            client = client.with_page_size(page_size);
+        }
+
+        if let Ok(kind) = env::var("SEAART_TYPE") {
+            client = client.with_kind(kind);
+        }
+
+        Ok(client)
+    }
+
+    pub fn with_order_by(mut self, order_by: impl Into<String>) -> Self {
+        self.order_by = order_by.into();
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: u16) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = kind.into();
+        self
+    }
+
+    pub fn with_upstreams(mut self, upstreams: Arc<Upstreams>) -> Self {
+        upstreams.register(UPSTREAM);
+        self.upstreams = upstreams;
+        self
+    }
+
+    pub async fn get_raw_value(
+        &self,
+        query_search: &str,
+        pages: u16,
+        tags: Vec<&String>,
+        order_by: Option<&str>,
+    ) -> Result<Value, UpstreamError> {
+        let search = self.search_body(query_search, tags, order_by);
+
+        self.get_page(&search, pages).await
+    }
+
+    pub fn search_body(
+        &self,
+        query_search: &str,
+        tags: Vec<&String>,
+        order_by: Option<&str>,
+    ) -> Value {
+        let keyword = query_search.replace('+', " ");
+        dbg!(&keyword);
+
+        json!({
+            "keyword": keyword,
+            "order_by": order_by.unwrap_or(&self.order_by),
+            "page_size": self.page_size,
+            "tags": tags,
+            "type": self.kind
+        })
+    }
+
+    pub async fn get_page(&self, search: &Value, pages: u16) -> Result<Value, UpstreamError> {
+        let url = format!("{}{LIST_PATH}", self.base_url);
+
+        let mut payload = search.clone();
+        payload["page"] = json!(pages);
+
+        let resp: Value = self
+            .upstreams
+            .send(UPSTREAM, self.client.post(url).json(&payload))
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
 }
 
 #[cfg(test)]
@@ -97,6 +252,57 @@ mod test {
         assert_eq!(url, image);
     }
 
+    #[test]
+    fn payload_test() {
+        let input = dummy_obj();
+        let category = String::from("anime");
+        let payload = item_to_payload(&input, Some(&category));
+
+        let author = payload.author.unwrap();
+        assert_eq!("1dad6ec26a4c7291a24f2cc92d21005d", author.id);
+        assert_eq!("baiwenyao111", author.name);
+        assert_eq!(
+            Some("65046a48c1075794ecdb3e8f1ef76f49".to_string()),
+            payload.model_id
+        );
+        assert_eq!(Some("v5".to_string()), payload.channel);
+        assert_eq!(1, payload.num_of_like);
+        assert_eq!(2, payload.num_of_collection);
+    }
+
+    #[tokio::test]
+    async fn mock_server_test() {
+        use axum::{routing::post, Json, Router};
+
+        let app = Router::new().route(
+            LIST_PATH,
+            post(|Json(body): Json<Value>| async move {
+                Json(json!({ "data": { "items": [dummy_obj()], "request": body } }))
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let client = SeaArtClient::new(format!("http://{addr}/"))
+            .unwrap()
+            .with_order_by("new")
+            .with_page_size(20);
+        let resp = client
+            .get_raw_value("gundam+mecha", 2, vec![], None)
+            .await
+            .unwrap();
+
+        let request = &resp["data"]["request"];
+        assert_eq!(1, resp["data"]["items"].as_array().unwrap().len());
+        assert_eq!("gundam mecha", request["keyword"]);
+        assert_eq!("new", request["order_by"]);
+        assert_eq!(20, request["page_size"]);
+        assert_eq!("community", request["type"]);
+    }
+
     fn dummy_obj() -> Value {
         let obj = json!(
         {
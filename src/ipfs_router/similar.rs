@@ -0,0 +1,271 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use sqlx::{Pool, Postgres};
+
+use super::near_duplicate::hamming;
+
+// Rows sharing a hash share a node
+#[derive(Debug)]
+struct Node {
+    hash: u64,
+    ids: Vec<i32>,
+    children: BTreeMap<u32, Node>,
+}
+
+impl Node {
+    fn new(hash: u64, id: i32) -> Self {
+        Node {
+            hash,
+            ids: vec![id],
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+// Burkhard-Keller tree over Hamming distance, children are keyed by their distance to the parent
+#[derive(Debug, Default)]
+pub struct BkTree {
+    root: Option<Node>,
+}
+
+impl BkTree {
+    // False when the tree already held the pair
+    pub fn insert(&mut self, hash: u64, id: i32) -> bool {
+        let Some(mut node) = self.root.as_mut() else {
+            self.root = Some(Node::new(hash, id));
+            return true;
+        };
+
+        loop {
+            let distance = hamming(node.hash, hash);
+            if distance == 0 {
+                if node.ids.contains(&id) {
+                    return false;
+                }
+                node.ids.push(id);
+                return true;
+            }
+
+            match node.children.entry(distance) {
+                Entry::Occupied(child) => node = child.into_mut(),
+                Entry::Vacant(child) => {
+                    child.insert(Node::new(hash, id));
+                    return true;
+                }
+            }
+        }
+    }
+
+    // Every (id, hash, distance) within `max_distance` of `hash`, in no particular order
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(i32, u64, u32)> {
+        let mut found = Vec::new();
+        let mut stack: Vec<&Node> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            let distance = hamming(node.hash, hash);
+
+            if distance <= max_distance {
+                found.extend(node.ids.iter().map(|&id| (id, node.hash, distance)));
+            }
+
+            // Triangle inequality: only children in [distance - max, distance + max] can match
+            let low = distance.saturating_sub(max_distance);
+            stack.extend(
+                node.children
+                    .range(low..=distance + max_distance)
+                    .map(|(_, child)| child),
+            );
+        }
+
+        found
+    }
+}
+
+// Stale tree entries allowed beyond one per indexed row before the tree is rebuilt
+const COMPACT_SLACK: usize = 1024;
+
+#[derive(Debug, Default)]
+struct IndexState {
+    tree: BkTree,
+    // Current hash of every indexed row, tree entries that disagree with it are stale
+    hashes: HashMap<i32, u64>,
+    // (id, hash) pairs in the tree, current and stale
+    tree_len: usize,
+}
+
+impl IndexState {
+    // Rows within `max_distance` of `hash` without the stale entries, closest and then lowest id first
+    fn neighbours(&self, hash: u64, max_distance: u32) -> Vec<(i32, u32)> {
+        let mut found: Vec<(i32, u32)> = self
+            .tree
+            .find(hash, max_distance)
+            .into_iter()
+            .filter(|(id, id_hash, _)| self.hashes.get(id) == Some(id_hash))
+            .map(|(id, _, distance)| (id, distance))
+            .collect();
+
+        found.sort_by_key(|&(id, distance)| (distance, id));
+        found
+    }
+
+    // Rebuilds the tree from the current hashes once stale entries outnumber the live ones
+    fn compact_if_stale(&mut self) {
+        if self.tree_len <= self.hashes.len() * 2 + COMPACT_SLACK {
+            return;
+        }
+
+        let mut tree = BkTree::default();
+        for (&id, &hash) in &self.hashes {
+            tree.insert(hash, id);
+        }
+
+        self.tree = tree;
+        self.tree_len = self.hashes.len();
+    }
+}
+
+// In-memory "more like this" index, built on startup and fed whenever a hash is stored.
+// Removing from a BK-tree is awkward, so changed and deleted rows are only dropped from
+// `hashes` and their old tree entries are filtered out until enough pile up for a rebuild.
+#[derive(Debug, Default)]
+pub struct SimilarIndex {
+    state: RwLock<IndexState>,
+}
+
+impl SimilarIndex {
+    pub async fn load(pool: &Pool<Postgres>) -> Result<Self, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, phash AS "phash!"
+            FROM ipfs_image
+            WHERE phash IS NOT NULL
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let index = SimilarIndex::default();
+        for row in rows {
+            index.insert(row.id, row.phash as u64);
+        }
+
+        Ok(index)
+    }
+
+    pub fn insert(&self, id: i32, hash: u64) {
+        let mut state = self.state.write().unwrap();
+
+        if state.hashes.insert(id, hash) != Some(hash) && state.tree.insert(hash, id) {
+            state.tree_len += 1;
+            state.compact_if_stale();
+        }
+    }
+
+    pub fn remove(&self, id: i32) {
+        let mut state = self.state.write().unwrap();
+
+        state.hashes.remove(&id);
+        state.compact_if_stale();
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.read().unwrap().hashes.len()
+    }
+
+    // Closest rows first, None when the row has no hash yet
+    pub fn similar(&self, id: i32, max_distance: u32, limit: usize) -> Option<Vec<(i32, u32)>> {
+        let state = self.state.read().unwrap();
+        let hash = *state.hashes.get(&id)?;
+
+        let mut found = state.neighbours(hash, max_distance);
+        found.retain(|&(other, _)| other != id);
+        found.truncate(limit);
+
+        Some(found)
+    }
+
+    // The closest row within `max_distance` of a hash that is not indexed yet
+    pub fn closest(&self, hash: u64, max_distance: u32) -> Option<(i32, u32)> {
+        let state = self.state.read().unwrap();
+
+        state.neighbours(hash, max_distance).into_iter().next()
+    }
+
+    // Every indexed row with its current hash, by id
+    pub fn entries(&self) -> Vec<(i32, u64)> {
+        let state = self.state.read().unwrap();
+        let mut entries: Vec<(i32, u64)> =
+            state.hashes.iter().map(|(&id, &hash)| (id, hash)).collect();
+        entries.sort_unstable();
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn similar_test() {
+        let index = SimilarIndex::default();
+        let hashes = [0b0000, 0b0001, 0b0011, 0b1111, 0xff00, 0b0001];
+
+        for (id, hash) in hashes.into_iter().enumerate() {
+            index.insert(id as i32, hash);
+        }
+
+        assert_eq!(Some(vec![(1, 1), (5, 1), (2, 2)]), index.similar(0, 2, 10));
+        assert_eq!(Some(vec![(1, 1)]), index.similar(0, 2, 1));
+
+        // Rehashed and deleted rows leave stale tree entries behind
+        index.insert(1, 0xffff);
+        index.remove(5);
+        assert_eq!(Some(vec![(2, 2)]), index.similar(0, 2, 10));
+        assert_eq!(Some(vec![(4, 8)]), index.similar(1, 8, 10));
+        assert_eq!(None, index.similar(5, 2, 10));
+        assert_eq!(5, index.size());
+
+        assert_eq!(Some((0, 1)), index.closest(0b0010, 3));
+        assert_eq!(None, index.closest(0xffff_0000_0000, 3));
+        assert_eq!(
+            vec![
+                (0, 0b0000),
+                (1, 0xffff),
+                (2, 0b0011),
+                (3, 0b1111),
+                (4, 0xff00)
+            ],
+            index.entries()
+        );
+
+        // Going back to an earlier hash does not list the row twice
+        index.insert(1, 0b0001);
+        assert_eq!(Some(vec![(1, 1), (2, 2)]), index.similar(0, 2, 10));
+
+        // Stale entries of a row rehashed over and over are compacted away
+        for hash in 0..5000u64 {
+            index.insert(3, hash << 20);
+        }
+        let tree_len = index.state.read().unwrap().tree_len;
+        assert!(tree_len <= index.size() * 2 + COMPACT_SLACK);
+        assert_eq!(Some(vec![(1, 1), (2, 2)]), index.similar(0, 2, 10));
+
+        let mut tree = BkTree::default();
+        for (id, hash) in (0..500u64).map(|i| (i as i32, i.wrapping_mul(0x9e37_79b9_7f4a_7c15))) {
+            tree.insert(hash, id);
+        }
+        let query = 7u64.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ 0b101;
+        let mut expected: Vec<i32> = (0..500u64)
+            .filter(|i| hamming(i.wrapping_mul(0x9e37_79b9_7f4a_7c15), query) <= 20)
+            .map(|i| i as i32)
+            .collect();
+        let mut found: Vec<i32> = tree.find(query, 20).into_iter().map(|f| f.0).collect();
+        expected.sort();
+        found.sort();
+        assert_eq!(expected, found);
+    }
+}
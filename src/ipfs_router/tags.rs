@@ -0,0 +1,75 @@
+use serde::{Deserialize, Deserializer};
+
+// Splits comma separated values, trims and lowercases them and drops empty or repeated tags
+pub fn normalize_tags<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+
+    for tag in values.into_iter().flat_map(|value| value.split(',')) {
+        let tag = tag.trim().to_lowercase();
+
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    tags
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TagsInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Accepts `"anime,mecha"` as well as `["anime", "mecha"]`
+pub fn deserialize_tags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tags = match TagsInput::deserialize(deserializer)? {
+        TagsInput::One(value) => normalize_tags([value.as_str()]),
+        TagsInput::Many(values) => normalize_tags(values.iter().map(String::as_str)),
+    };
+
+    Ok(tags)
+}
+
+// Every `tags` value of a query string, repeated or comma separated
+pub fn query_tags(params: &[(String, String)]) -> Vec<String> {
+    normalize_tags(
+        params
+            .iter()
+            .filter(|(key, _)| key == "tags")
+            .map(|(_, value)| value.as_str()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tags_test() {
+        let params = vec![
+            ("tags".to_string(), "Anime, mecha".to_string()),
+            ("q".to_string(), "gundam".to_string()),
+            ("tags".to_string(), "mecha".to_string()),
+            ("tags".to_string(), "unreal engine,,".to_string()),
+        ];
+        assert_eq!(vec!["anime", "mecha", "unreal engine"], query_tags(&params));
+
+        #[derive(Deserialize)]
+        struct Payload {
+            #[serde(default, deserialize_with = "deserialize_tags")]
+            tags: Vec<String>,
+        }
+
+        let one: Payload = serde_json::from_str(r#"{ "tags": "anime,Mecha" }"#).unwrap();
+        let many: Payload = serde_json::from_str(r#"{ "tags": ["anime", " mecha "] }"#).unwrap();
+        let none: Payload = serde_json::from_str("{}").unwrap();
+        assert_eq!(vec!["anime", "mecha"], one.tags);
+        assert_eq!(one.tags, many.tags);
+        assert!(none.tags.is_empty());
+    }
+}
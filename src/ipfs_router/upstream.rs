@@ -0,0 +1,494 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+
+use super::ipfs_model::datetime_to_string;
+
+// A Retry-After longer than this is not worth holding a request open for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+// Image and page hosts each get a breaker, past this many the idle ones are dropped
+const MAX_BREAKERS: usize = 1024;
+const BREAKER_IDLE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff with equal jitter, so retries of parallel jobs spread out
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+
+        ceiling / 2 + (ceiling / 2).mul_f64(fastrand::f64())
+    }
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    CircuitOpen {
+        upstream: String,
+        retry_in: Duration,
+    },
+    Status {
+        upstream: String,
+        status: StatusCode,
+    },
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CircuitOpen { upstream, retry_in } => write!(
+                f,
+                "{upstream} circuit is open, retry in {}s",
+                retry_in.as_secs()
+            ),
+            Self::Status { upstream, status } => write!(f, "{upstream} responded with {status}"),
+            Self::Request(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(err: reqwest::Error) -> Self {
+        UpstreamError::Request(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    // The cooldown passed and a single probe request is in flight. A probe that never reports
+    // back, because its caller was dropped, lets the next one through once `until` passes.
+    HalfOpen { until: Instant },
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    last_failure_at: Option<DateTime<Utc>>,
+    last_used: Instant,
+    // Registered upstreams are always listed, so they are never evicted
+    registered: bool,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            last_error: None,
+            last_failure_at: None,
+            last_used: Instant::now(),
+            registered: false,
+        }
+    }
+}
+
+impl Breaker {
+    // Unused for `idle` and not holding calls back, dropping it loses nothing but its history
+    fn is_idle(&self, now: Instant, idle: Duration) -> bool {
+        let blocking = match self.state {
+            BreakerState::Closed => false,
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } => now < until,
+        };
+
+        !self.registered && !blocking && now.saturating_duration_since(self.last_used) >= idle
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UpstreamStatus {
+    name: String,
+    state: &'static str,
+    consecutive_failures: u32,
+    retry_in_ms: Option<u128>,
+    last_error: Option<String>,
+    last_failure_at: Option<String>,
+}
+
+// Retries and a circuit breaker for every upstream the service talks to
+#[derive(Debug)]
+pub struct Upstreams {
+    retry: RetryPolicy,
+    failure_threshold: u32,
+    cooldown: Duration,
+    max_breakers: usize,
+    breaker_idle: Duration,
+    breakers: Mutex<BTreeMap<String, Breaker>>,
+}
+
+impl Default for Upstreams {
+    fn default() -> Self {
+        Upstreams {
+            retry: RetryPolicy::default(),
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+            max_breakers: MAX_BREAKERS,
+            breaker_idle: BREAKER_IDLE,
+            breakers: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Upstreams {
+    // UPSTREAM_MAX_ATTEMPTS, UPSTREAM_FAILURE_THRESHOLD and UPSTREAM_COOLDOWN_SECS override the defaults
+    pub fn from_env() -> Self {
+        let mut upstreams = Upstreams::default();
+        let var = |key: &str| env::var(key).ok().and_then(|val| val.parse::<u32>().ok());
+
+        if let Some(max_attempts) = var("UPSTREAM_MAX_ATTEMPTS") {
+            upstreams.retry.max_attempts = max_attempts.max(1);
+        }
+
+        if let Some(threshold) = var("UPSTREAM_FAILURE_THRESHOLD") {
+            upstreams.failure_threshold = threshold.max(1);
+        }
+
+        if let Some(cooldown) = var("UPSTREAM_COOLDOWN_SECS") {
+            upstreams.cooldown = Duration::from_secs(cooldown.into());
+        }
+
+        upstreams
+    }
+
+    // Makes an upstream show up in the status before its first call
+    pub fn register(&self, name: &str) {
+        self.breakers
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .registered = true;
+    }
+
+    fn acquire(&self, name: &str) -> Result<(), UpstreamError> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let now = Instant::now();
+
+        if breakers.len() >= self.max_breakers && !breakers.contains_key(name) {
+            breakers.retain(|_, breaker| !breaker.is_idle(now, self.breaker_idle));
+        }
+
+        let breaker = breakers.entry(name.to_string()).or_default();
+        breaker.last_used = now;
+
+        match breaker.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } if now >= until => {
+                breaker.state = BreakerState::HalfOpen {
+                    until: now + self.cooldown,
+                };
+                Ok(())
+            }
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } => {
+                Err(UpstreamError::CircuitOpen {
+                    upstream: name.to_string(),
+                    retry_in: until - now,
+                })
+            }
+        }
+    }
+
+    fn record_success(&self, name: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(name.to_string()).or_default();
+
+        breaker.state = BreakerState::Closed;
+        breaker.consecutive_failures = 0;
+    }
+
+    fn record_failure(&self, name: &str, error: String) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(name.to_string()).or_default();
+
+        breaker.consecutive_failures += 1;
+        breaker.last_error = Some(error);
+        breaker.last_failure_at = Some(Utc::now());
+
+        if matches!(breaker.state, BreakerState::HalfOpen { .. })
+            || breaker.consecutive_failures >= self.failure_threshold
+        {
+            breaker.state = BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            };
+        }
+    }
+
+    // Sends the request, retrying timeouts, 429 and 5xx responses until the attempts run out
+    pub async fn send(
+        &self,
+        name: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, UpstreamError> {
+        self.acquire(name)?;
+
+        let mut request = Some(request);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            // Bodies that cannot be cloned only get a single attempt
+            let (current, last) = match request.as_ref().and_then(RequestBuilder::try_clone) {
+                Some(current) if attempt < self.retry.max_attempts => (current, false),
+                _ => (request.take().unwrap(), true),
+            };
+
+            let wait = match current.send().await {
+                Ok(resp) if !is_retryable(resp.status()) => {
+                    self.record_success(name);
+                    return Ok(resp);
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    let retry_after = retry_after(&resp);
+
+                    if last || retry_after.is_some_and(|wait| wait > MAX_RETRY_AFTER) {
+                        self.record_failure(name, status.to_string());
+                        return Err(UpstreamError::Status {
+                            upstream: name.to_string(),
+                            status,
+                        });
+                    }
+
+                    retry_after.unwrap_or_else(|| self.retry.backoff(attempt))
+                }
+                Err(err) if !last && (err.is_timeout() || err.is_connect()) => {
+                    self.retry.backoff(attempt)
+                }
+                Err(err) => {
+                    self.record_failure(name, err.to_string());
+                    return Err(err.into());
+                }
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        let breakers = self.breakers.lock().unwrap();
+        let now = Instant::now();
+
+        breakers
+            .iter()
+            .map(|(name, breaker)| {
+                let (state, retry_in_ms) = match breaker.state {
+                    BreakerState::Closed => ("closed", None),
+                    BreakerState::Open { until } => (
+                        "open",
+                        Some(until.saturating_duration_since(now).as_millis()),
+                    ),
+                    BreakerState::HalfOpen { until } => (
+                        "half_open",
+                        Some(until.saturating_duration_since(now).as_millis()),
+                    ),
+                };
+
+                UpstreamStatus {
+                    name: name.clone(),
+                    state,
+                    consecutive_failures: breaker.consecutive_failures,
+                    retry_in_ms,
+                    last_error: breaker.last_error.clone(),
+                    last_failure_at: datetime_to_string(breaker.last_failure_at),
+                }
+            })
+            .collect()
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+
+    parse_retry_after(value, Utc::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_after_test() {
+        let now = DateTime::parse_from_rfc3339("2023-08-07T06:53:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after("120", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(9)),
+            parse_retry_after("Mon, 07 Aug 2023 06:53:39 GMT", now)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_retry_after("Mon, 07 Aug 2023 06:00:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+
+        let retry = RetryPolicy::default();
+        assert!(retry.backoff(1) >= Duration::from_millis(500));
+        assert!(retry.backoff(10) <= retry.max_delay);
+    }
+
+    #[test]
+    fn evict_test() {
+        let upstreams = Upstreams {
+            failure_threshold: 1,
+            max_breakers: 3,
+            breaker_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        upstreams.register("seaart");
+
+        upstreams.acquire("a.example").unwrap();
+        upstreams.acquire("b.example").unwrap();
+        upstreams.record_failure("b.example", "502 Bad Gateway".to_string());
+        upstreams.acquire("c.example").unwrap();
+
+        // Registered and open breakers stay, the idle host goes
+        let names: Vec<String> = upstreams.status().into_iter().map(|s| s.name).collect();
+        assert_eq!(vec!["b.example", "c.example", "seaart"], names);
+    }
+
+    #[test]
+    fn breaker_test() {
+        let cooldown = Duration::from_millis(20);
+        let upstreams = Upstreams {
+            failure_threshold: 2,
+            cooldown,
+            ..Default::default()
+        };
+
+        upstreams.acquire("seaart").unwrap();
+        upstreams.record_failure("seaart", "502 Bad Gateway".to_string());
+        upstreams.acquire("seaart").unwrap();
+        upstreams.record_failure("seaart", "502 Bad Gateway".to_string());
+        assert_eq!("open", upstreams.status()[0].state);
+        assert!(upstreams.acquire("seaart").is_err());
+
+        // Cooldown is over, one probe goes through while everything else fails fast
+        std::thread::sleep(cooldown);
+        upstreams.acquire("seaart").unwrap();
+        assert!(matches!(
+            upstreams.acquire("seaart"),
+            Err(UpstreamError::CircuitOpen { retry_in, .. }) if retry_in > Duration::ZERO
+        ));
+
+        // The probe was dropped without reporting, another one is let through later
+        std::thread::sleep(cooldown);
+        upstreams.acquire("seaart").unwrap();
+        assert_eq!("half_open", upstreams.status()[0].state);
+
+        upstreams.record_success("seaart");
+        let status = &upstreams.status()[0];
+        assert_eq!("closed", status.state);
+        assert_eq!(0, status.consecutive_failures);
+    }
+
+    #[tokio::test]
+    async fn retry_server_test() {
+        use std::sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        };
+
+        use axum::{response::IntoResponse, routing::get, Router};
+
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&hits);
+
+        let app = Router::new().route(
+            "/",
+            get(move || async move {
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "0")]).into_response(),
+                    1 => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+                    _ => "ok".into_response(),
+                }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let upstreams = Upstreams {
+            retry: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(5),
+            },
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+
+        let resp = upstreams
+            .send("mock", client.get(format!("http://{addr}/")))
+            .await
+            .unwrap();
+        assert_eq!("ok", resp.text().await.unwrap());
+        assert_eq!(3, hits.load(Ordering::SeqCst));
+        assert_eq!("closed", upstreams.status()[0].state);
+
+        let missing = upstreams
+            .send("mock", client.get(format!("http://{addr}/missing")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, missing.status());
+    }
+}
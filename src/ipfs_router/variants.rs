@@ -0,0 +1,377 @@
+use std::{env, io::Cursor, path::PathBuf, str::FromStr};
+
+use eyre::Result;
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    ColorType, DynamicImage,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use super::{
+    content_rating::ContentRating, image_analysis::decode_image, image_download::DownloadedImage,
+};
+
+// Not done: lossy WebP and AVIF variants. They need libwebp and rav1e, which image only
+// wraps behind its webp-encoder and avif-encoder features and this build does not ship.
+// Until those land JPEG is the lossy format and `webp` stays opt-in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantFormat {
+    Jpeg,
+    // Lossless only, so usually larger than the original, kept for images with alpha
+    Webp,
+}
+
+impl VariantFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+impl FromStr for VariantFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "webp" => Ok(Self::Webp),
+            "avif" => Err("avif variants are not available yet, see VariantFormat".to_string()),
+            other => Err(format!("unknown variant format `{other}`")),
+        }
+    }
+}
+
+// Which variants get rendered and where they are written and served from
+#[derive(Debug, Clone)]
+pub struct VariantConfig {
+    pub widths: Vec<u32>,
+    pub thumb_size: u32,
+    pub formats: Vec<VariantFormat>,
+    pub quality: u8,
+    pub dir: PathBuf,
+    pub base_url: String,
+}
+
+impl Default for VariantConfig {
+    fn default() -> Self {
+        VariantConfig {
+            widths: vec![320, 640, 1024],
+            thumb_size: 256,
+            formats: vec![VariantFormat::Jpeg],
+            quality: 80,
+            dir: PathBuf::from("variants"),
+            base_url: "/variants".to_string(),
+        }
+    }
+}
+
+impl VariantConfig {
+    // VARIANT_WIDTHS and VARIANT_FORMATS are comma separated, VARIANT_THUMB_SIZE is the
+    // side of the square thumbnail, VARIANT_QUALITY the JPEG quality from 1 to 100,
+    // VARIANT_DIR and VARIANT_BASE_URL where files go and are served
+    pub fn from_env() -> Self {
+        let mut config = VariantConfig::default();
+
+        if let Ok(widths) = env::var("VARIANT_WIDTHS") {
+            config.widths = widths
+                .split(',')
+                .map(|width| width.trim().parse().expect("VARIANT_WIDTHS is invalid"))
+                .collect();
+        }
+
+        if let Ok(formats) = env::var("VARIANT_FORMATS") {
+            config.formats = formats
+                .split(',')
+                .map(|format| format.parse().expect("VARIANT_FORMATS is invalid"))
+                .collect();
+        }
+
+        if let Ok(size) = env::var("VARIANT_THUMB_SIZE") {
+            config.thumb_size = size.parse().expect("VARIANT_THUMB_SIZE is invalid");
+        }
+
+        if let Ok(quality) = env::var("VARIANT_QUALITY") {
+            config.quality = quality
+                .parse()
+                .ok()
+                .filter(|quality| (1..=100).contains(quality))
+                .expect("VARIANT_QUALITY is invalid");
+        }
+
+        if let Ok(dir) = env::var("VARIANT_DIR") {
+            config.dir = PathBuf::from(dir);
+        }
+
+        if let Ok(base_url) = env::var("VARIANT_BASE_URL") {
+            config.base_url = base_url.trim_end_matches('/').to_string();
+        }
+
+        config
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageVariant {
+    pub kind: String,
+    pub format: String,
+    pub width: i32,
+    pub height: i32,
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub struct RenderedVariant {
+    pub kind: &'static str,
+    pub format: VariantFormat,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl RenderedVariant {
+    fn file_name(&self) -> String {
+        format!("{}-{}.{}", self.kind, self.width, self.format.as_str())
+    }
+}
+
+// A square thumbnail plus every configured width narrower than the original, never upscaled
+pub fn render_variants(img: &DynamicImage, config: &VariantConfig) -> Result<Vec<RenderedVariant>> {
+    let thumb_size = config.thumb_size.min(img.width()).min(img.height());
+    let mut resized = vec![(
+        "thumb",
+        img.resize_to_fill(thumb_size, thumb_size, FilterType::CatmullRom),
+    )];
+
+    for &width in &config.widths {
+        if width < img.width() {
+            resized.push(("width", img.resize(width, u32::MAX, FilterType::CatmullRom)));
+        }
+    }
+
+    let mut variants = Vec::new();
+
+    for (kind, img) in resized {
+        for &format in &config.formats {
+            variants.push(RenderedVariant {
+                kind,
+                format,
+                width: img.width(),
+                height: img.height(),
+                bytes: encode(&img, format, config.quality)?,
+            });
+        }
+    }
+
+    Ok(variants)
+}
+
+fn encode(img: &DynamicImage, format: VariantFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+
+    match format {
+        // JPEG has no alpha channel, transparent pixels come out with their stored color
+        VariantFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, quality).encode(
+            img.to_rgb8().as_raw(),
+            img.width(),
+            img.height(),
+            ColorType::Rgb8,
+        )?,
+        VariantFormat::Webp => {
+            // Alpha is only kept for images that have it
+            let (raw, color) = if img.color().has_alpha() {
+                (img.to_rgba8().into_raw(), ColorType::Rgba8)
+            } else {
+                (img.to_rgb8().into_raw(), ColorType::Rgb8)
+            };
+            WebPEncoder::new_lossless(Cursor::new(&mut bytes)).encode(
+                &raw,
+                img.width(),
+                img.height(),
+                color,
+            )?
+        }
+    }
+
+    Ok(bytes)
+}
+
+// `thumb-256.jpg` back into its kind, width and format, None for anything else
+fn parse_file_name(file_name: &str) -> Option<(&str, i32, VariantFormat)> {
+    let (stem, format) = file_name.rsplit_once('.')?;
+    let (kind, width) = stem.split_once('-')?;
+
+    if !["thumb", "width"].contains(&kind) {
+        return None;
+    }
+
+    Some((kind, width.parse().ok()?, format.parse().ok()?))
+}
+
+// The file of a stored variant, only for rows the default listing shows: safe and not
+// quarantined, so a guessed id does not reach restricted images
+pub async fn variant_file(
+    pool: &Pool<Postgres>,
+    id: i32,
+    file_name: &str,
+) -> Result<Option<(VariantFormat, PathBuf)>> {
+    let Some((kind, width, format)) = parse_file_name(file_name) else {
+        return Ok(None);
+    };
+
+    let path = sqlx::query_scalar!(
+        r#"
+        SELECT image_variants.path
+        FROM image_variants
+        JOIN ipfs_image ON ipfs_image.id = image_variants.image_id
+        WHERE image_variants.image_id = $1 AND image_variants.kind = $2
+            AND image_variants.format = $3 AND image_variants.width = $4
+            AND content_rating = $5 AND NOT quarantined
+        "#,
+        id,
+        kind,
+        format.as_str(),
+        width,
+        ContentRating::Safe.as_str(),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(path.map(|path| (format, PathBuf::from(path))))
+}
+
+// The rows go with ON DELETE CASCADE, the files have to be removed by hand
+pub async fn remove_variants(config: &VariantConfig, id: i32) -> std::io::Result<()> {
+    match tokio::fs::remove_dir_all(config.dir.join(id.to_string())).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+// Renders, writes and records the variants of a downloaded file, replacing older ones
+pub async fn generate_variants(
+    pool: &Pool<Postgres>,
+    config: &VariantConfig,
+    id: i32,
+    image: &DownloadedImage,
+) -> Result<Vec<ImageVariant>> {
+    let bytes = image.bytes.clone();
+    let render_config = config.clone();
+    let rendered = tokio::task::spawn_blocking(move || {
+        let img = decode_image(&bytes)?;
+        render_variants(&img, &render_config)
+    })
+    .await??;
+
+    let dir = config.dir.join(id.to_string());
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let mut stored = Vec::new();
+
+    for variant in rendered {
+        let file_name = variant.file_name();
+        tokio::fs::write(dir.join(&file_name), &variant.bytes).await?;
+
+        let url = format!("{}/{id}/{file_name}", config.base_url);
+        let path = dir.join(&file_name).to_string_lossy().to_string();
+
+        let row = sqlx::query_as!(
+            ImageVariant,
+            r#"
+            INSERT INTO image_variants (image_id, kind, format, width, height, path, url, bytes)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (image_id, kind, format, width) DO UPDATE
+            SET height = EXCLUDED.height, path = EXCLUDED.path, url = EXCLUDED.url,
+                bytes = EXCLUDED.bytes, created = NOW()
+            RETURNING kind, format, width, height, url
+            "#,
+            id,
+            variant.kind,
+            variant.format.as_str(),
+            variant.width as i32,
+            variant.height as i32,
+            path,
+            url,
+            variant.bytes.len() as i32,
+        )
+        .fetch_one(pool)
+        .await?;
+
+        stored.push(row);
+    }
+
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    #[test]
+    fn render_test() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(800, 600, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let config = VariantConfig {
+            widths: vec![320, 640, 1024],
+            thumb_size: 128,
+            ..VariantConfig::default()
+        };
+
+        let variants = render_variants(&img, &config).unwrap();
+        let sizes: Vec<(&str, u32, u32)> = variants
+            .iter()
+            .map(|v| (v.kind, v.width, v.height))
+            .collect();
+        assert_eq!(
+            vec![
+                ("thumb", 128, 128),
+                ("width", 320, 240),
+                ("width", 640, 480)
+            ],
+            sizes
+        );
+        assert_eq!("width-640.jpg", variants[2].file_name());
+
+        let decoded = image::load_from_memory(&variants[1].bytes).unwrap();
+        assert_eq!((320, 240), (decoded.width(), decoded.height()));
+
+        // On noisy, photo-like pixels the lossy default stays well under lossless WebP
+        let mut rng = fastrand::Rng::with_seed(7);
+        let noisy = DynamicImage::ImageRgb8(RgbImage::from_fn(320, 240, |x, _| {
+            let base = (x % 256) as u8;
+            image::Rgb([base, base.wrapping_add(rng.u8(..32)), rng.u8(..)])
+        }));
+        let webp = encode(&noisy, VariantFormat::Webp, config.quality).unwrap();
+        let jpeg = encode(&noisy, VariantFormat::Jpeg, config.quality).unwrap();
+        assert!(jpeg.len() * 2 < webp.len());
+    }
+
+    #[test]
+    fn file_name_test() {
+        assert_eq!(
+            Some(("thumb", 256, VariantFormat::Jpeg)),
+            parse_file_name("thumb-256.jpg")
+        );
+        assert_eq!(
+            Some(("width", 640, VariantFormat::Webp)),
+            parse_file_name("width-640.webp")
+        );
+        assert_eq!(None, parse_file_name("original"));
+        assert_eq!(None, parse_file_name("thumb-256.avif"));
+        assert_eq!(None, parse_file_name("../thumb-256.jpg"));
+        assert!("avif".parse::<VariantFormat>().is_err());
+    }
+}
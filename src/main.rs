@@ -1,31 +1,70 @@
 use axum::{
+    extract::FromRef,
     http::{
-        header::{ACCEPT, CONTENT_TYPE},
-        Method, StatusCode,
+        header::{ACCEPT, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+        HeaderName, Method, StatusCode,
     },
-    routing::{delete, get, patch, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use dotenv::dotenv;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, sync::Arc};
 use tower_http::cors::{Any, CorsLayer};
 
 mod ipfs_router;
 
 use ipfs_router::{
-    begin_insert, contact_form, create_data, delete_data, fetch_single, get_all_ipfs,
-    get_all_pretty, test_query, update_data,
+    add_album_images, backfill_parsed_prompts, backfill_placeholders, cancel_ingest, contact_form,
+    create_album, create_category, create_data, create_search, delete_album, delete_category,
+    delete_data, delete_search, download_archive, fail_interrupted_jobs, fetch_single, get_album,
+    get_album_images, get_albums, get_all_ipfs, get_all_pretty, get_categories, get_category,
+    get_duplicates, get_history, get_search_runs, get_searches, get_similar, get_upstreams,
+    get_variant, ingest_status, normalize_categories, pin_image, preview_ingest,
+    remove_album_image, render_image, reorder_album, revert_image, spawn_scheduler, start_ingest,
+    test_query, update_album, update_category, update_data, DuplicatePolicy, FetchGuard,
+    HtmlSource, ImageDownloader, IngestPolicy, IpfsStorage, JobRegistry, PreconditionPolicy,
+    RenderConfig, SeaArtClient, SimilarIndex, Upstreams, VariantConfig, ACTOR_HEADER,
 };
 
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pool: Pool<Postgres>,
+    ingest_policy: Arc<IngestPolicy>,
+    duplicate_policy: Arc<DuplicatePolicy>,
+    jobs: Arc<JobRegistry>,
+    seaart: Arc<SeaArtClient>,
+    html: Arc<HtmlSource>,
+    ipfs: Arc<IpfsStorage>,
+    downloader: Arc<ImageDownloader>,
+    upstreams: Arc<Upstreams>,
+    similar: Arc<SimilarIndex>,
+    variants: Arc<VariantConfig>,
+    render: Arc<RenderConfig>,
+    preconditions: Arc<PreconditionPolicy>,
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([ACCEPT, CONTENT_TYPE])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::PUT,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            ACCEPT,
+            CONTENT_TYPE,
+            IF_MATCH,
+            IF_NONE_MATCH,
+            HeaderName::from_static(ACTOR_HEADER),
+        ])
+        .expose_headers([ETAG])
         .allow_origin(Any);
 
     let pool: Pool<Postgres> = PgPoolOptions::new()
@@ -34,6 +73,57 @@ async fn main() {
         .await
         .unwrap();
 
+    let interrupted = fail_interrupted_jobs(&pool).await.unwrap();
+    println!("Marked {interrupted} interrupted ingest jobs as failed");
+
+    let parsed = backfill_parsed_prompts(&pool).await.unwrap();
+    println!("Parsed {parsed} stored prompts");
+
+    let categories = normalize_categories(&pool).await.unwrap();
+    println!("Normalized {categories} category slugs");
+
+    let similar = Arc::new(SimilarIndex::load(&pool).await.unwrap());
+    println!("Indexed {} perceptual hashes", similar.size());
+
+    let upstreams = Arc::new(Upstreams::from_env());
+    let variants = Arc::new(VariantConfig::from_env());
+    let fetch_guard = FetchGuard::from_env();
+
+    let state = AppState {
+        pool,
+        ingest_policy: Arc::new(IngestPolicy::from_env()),
+        duplicate_policy: Arc::new(DuplicatePolicy::from_env()),
+        jobs: Arc::new(JobRegistry::default()),
+        seaart: Arc::new(
+            SeaArtClient::from_env()
+                .unwrap()
+                .with_upstreams(upstreams.clone()),
+        ),
+        html: Arc::new(
+            HtmlSource::from_env(fetch_guard)
+                .unwrap()
+                .with_upstreams(upstreams.clone()),
+        ),
+        ipfs: Arc::new(IpfsStorage::from_env(upstreams.clone()).unwrap()),
+        downloader: Arc::new(ImageDownloader::from_env(upstreams.clone(), fetch_guard).unwrap()),
+        upstreams,
+        similar,
+        variants: variants.clone(),
+        render: Arc::new(RenderConfig::from_env()),
+        preconditions: Arc::new(PreconditionPolicy::from_env()),
+    };
+
+    spawn_scheduler(state.clone());
+
+    // Downloads every file that lacks a placeholder, so it runs behind the server in small batches
+    let backfill = state.clone();
+    tokio::spawn(async move {
+        match backfill_placeholders(&backfill.pool, &backfill.similar, &backfill.downloader).await {
+            Ok(filled) => println!("Filled {filled} missing placeholders"),
+            Err(err) => eprintln!("Placeholder backfill failed: {err}"),
+        }
+    });
+
     let app = Router::new()
         .route("/", get(home))
         .route("/get_all", get(get_all_ipfs))
@@ -42,9 +132,45 @@ async fn main() {
         .route("/update_data/:id", patch(update_data))
         .route("/delete_data/:id", delete(delete_data))
         .route("/fetch_single/:id", get(fetch_single))
-        .route("/begin_insert", get(begin_insert))
+        .route("/pin_image/:id", post(pin_image))
+        .route("/images/archive", get(download_archive))
+        .route("/images/:id/history", get(get_history))
+        .route(
+            "/images/:id/history/:revision_id/revert",
+            post(revert_image),
+        )
+        .route("/images/:id/similar", get(get_similar))
+        .route("/images/:id/render", get(render_image))
+        .route("/variants/:id/:file_name", get(get_variant))
+        .route("/ingest", post(start_ingest))
+        .route("/ingest/preview", post(preview_ingest))
+        .route("/ingest/:id", get(ingest_status))
+        .route("/ingest/:id/cancel", post(cancel_ingest))
+        .route("/saved_searches", get(get_searches).post(create_search))
+        .route("/saved_searches/:id", delete(delete_search))
+        .route("/saved_searches/:id/runs", get(get_search_runs))
+        .route("/categories", get(get_categories).post(create_category))
+        .route(
+            "/categories/:slug",
+            get(get_category)
+                .patch(update_category)
+                .delete(delete_category),
+        )
+        .route("/albums", get(get_albums).post(create_album))
+        .route(
+            "/albums/:id",
+            get(get_album).patch(update_album).delete(delete_album),
+        )
+        .route(
+            "/albums/:id/images",
+            get(get_album_images).post(add_album_images),
+        )
+        .route("/albums/:id/images/:image_id", delete(remove_album_image))
+        .route("/albums/:id/order", put(reorder_album))
+        .route("/upstreams", get(get_upstreams))
+        .route("/duplicates", get(get_duplicates))
         .route("/test_search_query", get(test_query))
-        .with_state(pool)
+        .with_state(state)
         .route("/contact_form", post(contact_form))
         .layer(cors);
 